@@ -0,0 +1,272 @@
+#![allow(dead_code)]
+
+// Decides when to announce to the tracker and with which event. It never looks at the clock itself: every call
+// takes `now`, so the caller owns the event loop and tests can use made-up Instants.
+//
+// Lifecycle: `started` once, then a regular announce every `interval`, `completed` when the download finishes
+// and `stopped` on shutdown. Failed announces are retried with exponential backoff and keep their event, so a
+// `started` that failed is sent again as `started`.
+
+use std::time::{Duration, Instant};
+
+use crate::tracker_request::{AnnounceEvent, AnnounceResponse};
+
+// Used until the tracker tells us its interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const MIN_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledAnnounce {
+    Started,
+    Regular,
+    Completed,
+    Stopped,
+}
+
+impl ScheduledAnnounce {
+    /// The `event` parameter to send. Regular announces have none.
+    pub fn event(self) -> Option<AnnounceEvent> {
+        match self {
+            ScheduledAnnounce::Started => Some(AnnounceEvent::Started),
+            ScheduledAnnounce::Regular => None,
+            ScheduledAnnounce::Completed => Some(AnnounceEvent::Completed),
+            ScheduledAnnounce::Stopped => Some(AnnounceEvent::Stopped),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceScheduler {
+    next: ScheduledAnnounce,
+    next_at: Instant,
+    last_success: Option<Instant>,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    tracker_id: Option<String>,
+    failures: u32,
+    // The download finished but the tracker hasn't been told yet.
+    completed_pending: bool,
+    // Set once `stopped` went through, or on a stop before the tracker ever heard of us.
+    finished: bool,
+}
+
+impl AnnounceScheduler {
+    /// The first announce, `started`, is due right away.
+    pub fn new(now: Instant) -> AnnounceScheduler {
+        AnnounceScheduler {
+            next: ScheduledAnnounce::Started,
+            next_at: now,
+            last_success: None,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            tracker_id: None,
+            failures: 0,
+            completed_pending: false,
+            finished: false,
+        }
+    }
+
+    /// The announce to send now, if one is due.
+    pub fn poll(&self, now: Instant) -> Option<ScheduledAnnounce> {
+        if self.finished || now < self.next_at {
+            return None;
+        }
+        Some(self.next)
+    }
+
+    /// When the next announce is due. None once the scheduler is finished.
+    pub fn next_at(&self) -> Option<Instant> {
+        if self.finished { None } else { Some(self.next_at) }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// 'tracker id' from the last response that had one, to be sent back as 'trackerid'.
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+
+    pub fn on_success(&mut self, now: Instant, response: &AnnounceResponse) {
+        self.interval = response.interval;
+        self.min_interval = response.min_interval;
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+        self.failures = 0;
+        self.last_success = Some(now);
+
+        match self.next {
+            ScheduledAnnounce::Stopped => {
+                self.finished = true;
+                return;
+            }
+            ScheduledAnnounce::Completed => self.completed_pending = false,
+            ScheduledAnnounce::Started | ScheduledAnnounce::Regular => {}
+        }
+
+        if self.completed_pending {
+            self.next = ScheduledAnnounce::Completed;
+            self.next_at = self.earliest_allowed(now);
+        } else {
+            self.next = ScheduledAnnounce::Regular;
+            self.next_at = now + self.interval.max(self.min_interval.unwrap_or_default());
+        }
+    }
+
+    /// Retries the same announce after 15s, 30s, 60s, ... capped at 30 minutes.
+    pub fn on_failure(&mut self, now: Instant) {
+        let backoff = MIN_BACKOFF.saturating_mul(1 << self.failures.min(16)).min(MAX_BACKOFF);
+        self.failures += 1;
+        self.next_at = now + backoff;
+    }
+
+    /// Queues `completed`. It is sent as soon as `min interval` allows.
+    pub fn on_download_completed(&mut self, now: Instant) {
+        if self.finished || self.next == ScheduledAnnounce::Stopped {
+            return;
+        }
+        self.completed_pending = true;
+        // Trackers only count a completion from a client that started, so an unsent `started` goes first
+        // and `completed` follows it.
+        if self.next == ScheduledAnnounce::Regular {
+            self.next = ScheduledAnnounce::Completed;
+            self.failures = 0;
+            self.next_at = self.earliest_allowed(now);
+        }
+    }
+
+    /// Queues `stopped`, due right away. If the tracker never got our `started`, there is nothing to stop.
+    pub fn on_shutdown(&mut self, now: Instant) {
+        if self.last_success.is_none() {
+            self.finished = true;
+            return;
+        }
+        self.next = ScheduledAnnounce::Stopped;
+        self.failures = 0;
+        self.next_at = now;
+    }
+
+    fn earliest_allowed(&self, now: Instant) -> Instant {
+        match (self.last_success, self.min_interval) {
+            (Some(last), Some(min)) => now.max(last + min),
+            _ => now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(interval: u64, min_interval: Option<u64>) -> AnnounceResponse {
+        AnnounceResponse {
+            interval: Duration::from_secs(interval),
+            min_interval: min_interval.map(Duration::from_secs),
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: Vec::new(),
+        }
+    }
+
+    fn secs(t0: Instant, s: u64) -> Instant {
+        t0 + Duration::from_secs(s)
+    }
+
+    #[test]
+    fn full_lifecycle() {
+        let t0 = Instant::now();
+        let mut s = AnnounceScheduler::new(t0);
+        assert_eq!(s.poll(t0), Some(ScheduledAnnounce::Started));
+
+        s.on_success(t0, &response(1800, Some(900)));
+        assert_eq!(s.poll(secs(t0, 1799)), None);
+        assert_eq!(s.poll(secs(t0, 1800)), Some(ScheduledAnnounce::Regular));
+        assert_eq!(ScheduledAnnounce::Regular.event(), None);
+
+        s.on_success(secs(t0, 1800), &response(1800, Some(900)));
+        // min interval holds `completed` back.
+        s.on_download_completed(secs(t0, 2000));
+        assert_eq!(s.poll(secs(t0, 2000)), None);
+        assert_eq!(s.poll(secs(t0, 2700)), Some(ScheduledAnnounce::Completed));
+
+        s.on_success(secs(t0, 2700), &response(1800, Some(900)));
+        s.on_shutdown(secs(t0, 2800));
+        assert_eq!(s.poll(secs(t0, 2800)), Some(ScheduledAnnounce::Stopped));
+        s.on_success(secs(t0, 2800), &response(1800, None));
+        assert!(s.is_finished());
+        assert_eq!(s.next_at(), None);
+        assert_eq!(s.poll(secs(t0, 100_000)), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let t0 = Instant::now();
+        let mut s = AnnounceScheduler::new(t0);
+
+        let mut now = t0;
+        for expected in [15, 30, 60, 120] {
+            s.on_failure(now);
+            assert_eq!(s.next_at(), Some(now + Duration::from_secs(expected)));
+            now = s.next_at().unwrap();
+            // The failed event is retried as is.
+            assert_eq!(s.poll(now), Some(ScheduledAnnounce::Started));
+        }
+        for _ in 0..20 {
+            s.on_failure(now);
+        }
+        assert_eq!(s.next_at(), Some(now + MAX_BACKOFF));
+
+        // Success resets the backoff.
+        s.on_success(now, &response(60, None));
+        s.on_failure(now);
+        assert_eq!(s.next_at(), Some(now + MIN_BACKOFF));
+    }
+
+    #[test]
+    fn completed_waits_for_started() {
+        let t0 = Instant::now();
+        let mut s = AnnounceScheduler::new(t0);
+        s.on_download_completed(t0);
+        assert_eq!(s.poll(t0), Some(ScheduledAnnounce::Started));
+
+        s.on_success(t0, &response(1800, Some(30)));
+        assert_eq!(s.poll(secs(t0, 29)), None);
+        assert_eq!(s.poll(secs(t0, 30)), Some(ScheduledAnnounce::Completed));
+        s.on_success(secs(t0, 30), &response(1800, Some(30)));
+        assert_eq!(s.poll(secs(t0, 1830)), Some(ScheduledAnnounce::Regular));
+    }
+
+    #[test]
+    fn interval_never_below_min_interval() {
+        let t0 = Instant::now();
+        let mut s = AnnounceScheduler::new(t0);
+        s.on_success(t0, &response(10, Some(120)));
+        assert_eq!(s.next_at(), Some(secs(t0, 120)));
+    }
+
+    #[test]
+    fn remembers_tracker_id() {
+        let t0 = Instant::now();
+        let mut s = AnnounceScheduler::new(t0);
+        let mut first = response(60, None);
+        first.tracker_id = Some("abc".to_string());
+        s.on_success(t0, &first);
+        s.on_success(secs(t0, 60), &response(60, None));
+        assert_eq!(s.tracker_id(), Some("abc"));
+    }
+
+    #[test]
+    fn shutdown_before_started_needs_no_announce() {
+        let t0 = Instant::now();
+        let mut s = AnnounceScheduler::new(t0);
+        s.on_failure(t0);
+        s.on_shutdown(t0);
+        assert!(s.is_finished());
+        assert_eq!(s.poll(t0), None);
+    }
+}
@@ -1,217 +1,479 @@
-#[allow(unused)]
+#![allow(unused)]
 
 use std::fmt;
 use std::collections::BTreeMap;
 
-use crate::bencode::BencodeValue;
+use crate::bencode::{BencodeRef, BencodeValue};
+use crate::bencode_int::BencodeInt;
+
+/// Where and why decoding failed. `offset` is the byte index into the original input,
+/// `path` is the key path to the failing element, eg. `info.files[3].length` (empty at the root).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub offset: usize,
+    pub expected: String,
+    pub found: String,
+    pub path: String,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at byte {}", self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " in {}", self.path)?;
+        }
+        write!(f, ": expected {}, found {}", self.expected, self.found)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BdecodingError {
     // TODO: Figure out why we dont use the commented out errors.
-    // NullRoot(String),
-    // NonSingularRootItem(String),
-    // InvalidType(String),
-    MissingTerminator(String),
-    IntegerError(String),
-    ByteStringError(String),
-    ListError(String),
-    DictionaryError(String)
+    // InvalidType(ErrorContext),
+    NullRoot(ErrorContext),
+    NonSingularRootItem(ErrorContext),
+    MissingTerminator(ErrorContext),
+    IntegerError(ErrorContext),
+    ByteStringError(ErrorContext),
+    ListError(ErrorContext),
+    DictionaryError(ErrorContext),
+    DepthLimitExceeded(ErrorContext),
+    ItemLimitExceeded(ErrorContext),
+    StringLengthLimitExceeded(ErrorContext),
+    InputSizeLimitExceeded(ErrorContext)
+}
+
+impl BdecodingError {
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            BdecodingError::NullRoot(c)
+            | BdecodingError::NonSingularRootItem(c)
+            | BdecodingError::MissingTerminator(c)
+            | BdecodingError::IntegerError(c)
+            | BdecodingError::ByteStringError(c)
+            | BdecodingError::ListError(c)
+            | BdecodingError::DictionaryError(c)
+            | BdecodingError::DepthLimitExceeded(c)
+            | BdecodingError::ItemLimitExceeded(c)
+            | BdecodingError::StringLengthLimitExceeded(c)
+            | BdecodingError::InputSizeLimitExceeded(c) => c,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.context().offset
+    }
 }
 
 impl fmt::Display for BdecodingError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        let what = match self {
+            BdecodingError::NullRoot(_) => "empty input",
+            BdecodingError::NonSingularRootItem(_) => "trailing data after root item",
+            BdecodingError::MissingTerminator(_) => "missing terminator",
+            BdecodingError::IntegerError(_) => "invalid integer",
+            BdecodingError::ByteStringError(_) => "invalid bytestring",
+            BdecodingError::ListError(_) => "invalid list",
+            BdecodingError::DictionaryError(_) => "invalid dictionary",
+            BdecodingError::DepthLimitExceeded(_) => "nesting depth limit exceeded",
+            BdecodingError::ItemLimitExceeded(_) => "item limit exceeded",
+            BdecodingError::StringLengthLimitExceeded(_) => "bytestring length limit exceeded",
+            BdecodingError::InputSizeLimitExceeded(_) => "input size limit exceeded",
+        };
+        write!(f, "{} {}", what, self.context())
     }
 }
 
 impl std::error::Error for BdecodingError {}
 
-fn parse_i64(e: &[u8]) -> Result<i64, BdecodingError> {
-    match String::from_utf8(e.to_vec()) {
-        Ok(s) => match s.parse::<i64>() {
-            Ok(num) =>{
-                // Fail on cases like 042.
-                if num > 0 && s.chars().nth(0).unwrap() == '0'  {
-                    return Err(BdecodingError::IntegerError(format!("Chaithu: Number contains leading zero. s = {}", s)))
-                }
-                
-                // Fail on cases like -042.
-                if num < 0 && s.chars().nth(0).unwrap() == '-' && s.chars().nth(1).unwrap() == '0'  {
-                    return Err(BdecodingError::IntegerError(format!("Chaithu: Number contains leading zero. s = {}", s)))
-                }
+/// How strictly the input must follow BEP 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeMode {
+    /// Only canonical bencode: sorted unique keys, no leading zeros or -0, exactly one root element.
+    #[default]
+    Strict,
+    /// Accepts what real-world torrents and trackers emit: unsorted or duplicate keys (the last value wins),
+    /// non-canonical integers, empty input and trailing bytes. Every tolerance applied is reported as a `DecodeWarning`.
+    Lenient,
+}
 
-                // Fail on cases like -0 or 000
-                if num == 0 && s != "0" {
-                    return Err(BdecodingError::IntegerError(format!("Chaithu: Number is 0 but length of string is more than 1. s = {}", s)))
-                } 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeWarningKind {
+    EmptyInput,
+    TrailingBytes,
+    UnsortedKey,
+    DuplicateKey,
+    NonCanonicalInteger,
+}
 
-                Ok(num)
-            },
-            Err(err) => Err(BdecodingError::IntegerError(format!("Chaithu: {} denoting an integer was not actually an i64 integer. error: {}", &s, &err.to_string())))
-        }
-        Err(err) => Err(BdecodingError::IntegerError(format!("Chaithu: String denoting an integer was not UTF-8 valid.\n{}", &err.to_string())))
-    }
+/// Something lenient mode accepted that strict mode would have rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeWarning {
+    pub kind: DecodeWarningKind,
+    pub offset: usize,
+    pub path: String,
+    pub detail: String,
 }
- 
-fn get_corresponding_terminator(e: &[u8]) -> Result<usize, BdecodingError> {
-    // Assert here because we only call this function if the starting character actually has a l or a d.
-    assert!(e[0] == b'l' || e[0] == b'd');
-
-    let mut index = 1; // first index is either l or d.
-    while index < e.len() {
-        match e[index] {
-            b'e' => {return Ok(index);},
-            _ => {index += get_first_element_len(&e[index..])?;}
+
+impl fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} at byte {}", self.kind, self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " in {}", self.path)?;
         }
+        write!(f, ": {}", self.detail)
     }
-
-    return Err(BdecodingError::MissingTerminator(format!("Chaithu: Could not find corresponding terminating character 'e' for {}.", String::from_utf8_lossy(&e))));
 }
 
+/// Limits applied while decoding. Input from trackers and peers is untrusted: without these a hostile peer
+/// can overflow the stack with `llll...` or make us allocate for a huge claimed length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Maximum number of nested lists/dictionaries. The root container is depth 1.
+    pub max_depth: usize,
+    /// Maximum number of elements in the whole input, counting every integer, bytestring, list and dictionary value.
+    pub max_items: usize,
+    /// Maximum length of a single bytestring, including dictionary keys.
+    pub max_string_length: usize,
+    /// Maximum size of the whole input in bytes.
+    pub max_input_size: usize,
+    pub mode: DecodeMode,
+}
 
-fn get_first_index(e: &[u8], ch: u8) -> Result<usize, BdecodingError> {
-    match e.into_iter().position(|x| *x == ch) {
-        Some(i) => Ok(i),
-        None => Err(BdecodingError::MissingTerminator(format!("Chaithu: Expected {} to end with {} but didn't,", String::from_utf8_lossy(&e), String::from_utf8_lossy(&vec![ch]))))
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            max_depth: 512,
+            max_items: 1_000_000,
+            max_string_length: 64 * 1024 * 1024,
+            max_input_size: 64 * 1024 * 1024,
+            mode: DecodeMode::Strict,
+        }
     }
 }
-// If string consists of [1][2]..[n] elements. This function returns the number of bytes that stores the 1st element.
-// In particular if only one element exists then it returns the size of the vector.
-fn get_first_element_len(e: &[u8]) -> Result<usize, BdecodingError> {
-    if e.is_empty() {
-        return Ok(0);
+
+impl DecodeOptions {
+    pub fn lenient() -> Self {
+        DecodeOptions { mode: DecodeMode::Lenient, ..DecodeOptions::default() }
     }
+}
 
-    match e[0] {
-        b'i' =>  Ok(1 + get_first_index(&e, b'e')?)
-        ,
-        b'l' | b'd'  => {
-            Ok(get_corresponding_terminator(&e)? + 1)
-        },
-        _ => {
-            let colon_index = get_first_index(&e, b':')?;
-            let number = parse_i64(&e[0..colon_index])?;
+/// One step of a key path: a dictionary key or a list index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathSegment<'a> {
+    Key(&'a [u8]),
+    Index(usize),
+}
 
-            if number < 0 {
-                return Err(BdecodingError::ByteStringError(format!("Chaithu: Negative length string found in {}.", String::from_utf8_lossy(&e))));
+// Formats a path as info.files[3].length
+pub fn format_path(path: &[PathSegment]) -> String {
+    let mut ans = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(k) => {
+                if !ans.is_empty() {
+                    ans.push('.');
+                }
+                ans.push_str(&String::from_utf8_lossy(k));
             }
+            PathSegment::Index(i) => ans.push_str(&format!("[{}]", i)),
+        }
+    }
+    ans
+}
 
-            let len = number as usize;
-            let start_of_str = colon_index + 1;
-            let end_of_str = start_of_str + len;
+// Longest token we quote back in an error message. Anything longer is cut off so a bad 200 KB
+// string doesn't end up in the message.
+const MAX_TOKEN_LEN: usize = 32;
 
-            if end_of_str > e.len() {
-                return Err(BdecodingError::ByteStringError(format!("Chaithu: Length described exceeds byte string length in {}.", String::from_utf8_lossy(&e))));
-            }
-            
-            Ok(end_of_str)
-        }        
+fn describe_byte(b: Option<u8>) -> String {
+    match b {
+        None => "end of input".to_string(),
+        Some(b) if b.is_ascii_graphic() || b == b' ' => format!("'{}'", b as char),
+        Some(b) => format!("byte 0x{:02X}", b),
     }
 }
 
-// Parses byestring of the form: num:XXXXXX
-fn bdecode_bytestr(e: &[u8]) -> Result<BencodeValue, BdecodingError> {
-    // get_first_element_len(&e) will validate the string for me.
-    Ok(BencodeValue::ByteString(e[get_first_index(&e, b':')? + 1 .. get_first_element_len(&e)?].to_vec()))
+fn describe_bytes(s: &[u8]) -> String {
+    if s.len() > MAX_TOKEN_LEN {
+        format!("\"{}\"... ({} bytes)", String::from_utf8_lossy(&s[..MAX_TOKEN_LEN]), s.len())
+    } else {
+        format!("\"{}\"", String::from_utf8_lossy(s))
+    }
+}
+
+// Walks the input exactly once. Every decode_* method starts at `pos` and leaves `pos` just past the element it read,
+// so nested lists and dictionaries never have to be re-scanned to find where they end.
+struct Decoder<'a, 'p> {
+    input: &'a [u8],
+    pos: usize,
+    path: Vec<PathSegment<'a>>,
+    options: DecodeOptions,
+    depth: usize,
+    items: usize,
+    // If set, the raw bytes of the element at this path are recorded in `raw_span` while decoding.
+    raw_target: Option<&'p [PathSegment<'p>]>,
+    raw_span: Option<&'a [u8]>,
+    warnings: Vec<DecodeWarning>,
 }
 
-// Parses bytestring of the form: iXXXXXe
-fn bdecode_i64(e: &[u8]) -> Result<BencodeValue, BdecodingError> {
-    if e[0] != b'i' {
-        return Err(BdecodingError::IntegerError(format!("Expected integer {} to start with 'i'.", String::from_utf8_lossy(&e))))
+impl<'a, 'p> Decoder<'a, 'p> {
+    fn new(input: &'a [u8], options: DecodeOptions) -> Self {
+        Decoder { input, pos: 0, path: Vec::new(), options, depth: 0, items: 0, raw_target: None, raw_span: None, warnings: Vec::new() }
     }
-    if e[e.len()-1] != b'e' {
-        return Err(BdecodingError::IntegerError(format!("Expected integer {} to end with 'e'.", String::from_utf8_lossy(&e))))
+
+    // Called when a list or dictionary is opened. The caller decrements `depth` once it is closed.
+    fn enter_container(&mut self) -> Result<(), BdecodingError> {
+        self.depth += 1;
+        if self.depth > self.options.max_depth {
+            return Err(BdecodingError::DepthLimitExceeded(self.context(self.pos, format!("at most {} nested lists/dictionaries", self.options.max_depth), format!("depth {}", self.depth))));
+        }
+        Ok(())
     }
 
-    match parse_i64(&e[1..e.len()-1]){
-        Ok(num) => Ok(BencodeValue::Integer(num)),
-        Err(e) => Err(e) 
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
     }
-}
 
-// Parses bytestring of the form: lXXXXXe
-// TODO: This is slow because of O(n^2) time I think. But the files are not that big so its fine.
-// Consider llllll....i0eee....eeeeee worst case scenario.
-fn bdecode_list(e: &[u8]) -> Result<BencodeValue, BdecodingError> {
-    if e[0] != b'l' {
-        return Err(BdecodingError::ListError(format!("Expected integer {} to start with 'l'.", String::from_utf8_lossy(&e))))
+    // In strict mode returns `err`. In lenient mode records a warning instead and lets decoding carry on.
+    fn tolerate(&mut self, kind: DecodeWarningKind, err: BdecodingError) -> Result<(), BdecodingError> {
+        match self.options.mode {
+            DecodeMode::Strict => Err(err),
+            DecodeMode::Lenient => {
+                let ctx = err.context();
+                self.warnings.push(DecodeWarning {
+                    kind,
+                    offset: ctx.offset,
+                    path: ctx.path.clone(),
+                    detail: format!("expected {}, found {}", ctx.expected, ctx.found),
+                });
+                Ok(())
+            }
+        }
     }
-    if e[e.len()-1] != b'e' {
-        return Err(BdecodingError::ListError(format!("Expected integer {} to end with 'e'.", String::from_utf8_lossy(&e))))
+
+    fn context(&self, offset: usize, expected: impl Into<String>, found: impl Into<String>) -> ErrorContext {
+        ErrorContext {
+            offset,
+            expected: expected.into(),
+            found: found.into(),
+            path: format_path(&self.path),
+        }
     }
 
-    let e = &e[1..e.len()-1]; // trim the first and last character.
-    let mut left_index = 0;
-    let mut ans = Vec::<BencodeValue>::new();
+    // Parses the textual form of a bencode integer, ie. the XXX in iXXXe or XXX:, and moves past `terminator`.
+    fn parse_integer(&mut self, terminator: u8) -> Result<BencodeInt, BdecodingError> {
+        let start = self.pos;
+        let negative = self.peek() == Some(b'-');
+        if negative {
+            self.pos += 1;
+        }
 
-    while left_index < e.len() {
-        let right_index = left_index + get_first_element_len(&e[left_index..])?;
-        ans.push(bdecode_element(&e[left_index..right_index])?);
-        left_index = right_index;
-    }
+        let digits_start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        let digits = &self.input[digits_start..self.pos];
 
-    Ok(BencodeValue::List(ans))
-}
+        match self.peek() {
+            None => {
+                return Err(BdecodingError::MissingTerminator(self.context(self.pos, format!("digit or '{}'", terminator as char), "end of input")));
+            }
+            Some(b) if b != terminator || digits.is_empty() => {
+                return Err(BdecodingError::IntegerError(self.context(self.pos, "integer digit", describe_byte(Some(b)))));
+            }
+            Some(_) => {}
+        }
+
+        // Fail on cases like 042, -042 and 00.
+        if digits.len() > 1 && digits[0] == b'0' {
+            let err = BdecodingError::IntegerError(self.context(digits_start, "integer without leading zero", describe_bytes(&self.input[start..self.pos])));
+            self.tolerate(DecodeWarningKind::NonCanonicalInteger, err)?;
+        }
 
-// Parses bytestring of the form: dXXXXXe
-// TODO: This is slow because of O(n^2) time I think. But the files are not that big so its fine.
-// Consider ddddddd....4:abcdi0eee....eeeeee worst case scenario.
-fn bdecode_dicitionary(e: &[u8]) -> Result<BencodeValue, BdecodingError> {
-    if e[0] != b'd' {
-        return Err(BdecodingError::ListError(format!("Expected integer {} to start with 'd'.", String::from_utf8_lossy(&e))))
+        // Fail on -0.
+        if negative && digits.iter().all(|d| *d == b'0') {
+            let err = BdecodingError::IntegerError(self.context(start, "non-zero integer after '-'", describe_bytes(&self.input[start..self.pos])));
+            self.tolerate(DecodeWarningKind::NonCanonicalInteger, err)?;
+        }
+
+        // The bytes are ASCII digits with an optional '-' so this is valid UTF-8.
+        let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+        let num = BencodeInt::parse(text).unwrap();
+
+        self.pos += 1; // skip the terminator.
+        Ok(num)
     }
-    if e[e.len()-1] != b'e' {
-        return Err(BdecodingError::ListError(format!("Expected integer {} to end with 'e'.", String::from_utf8_lossy(&e))))
+
+    fn decode_element(&mut self) -> Result<BencodeRef<'a>, BdecodingError> {
+        let start = self.pos;
+        self.items += 1;
+        if self.items > self.options.max_items {
+            return Err(BdecodingError::ItemLimitExceeded(self.context(start, format!("at most {} items", self.options.max_items), format!("item {}", self.items))));
+        }
+
+        let ans = match self.peek() {
+            Some(b'i') => self.decode_integer(),
+            Some(b'l') => self.decode_list(),
+            Some(b'd') => self.decode_dictionary(),
+            Some(_) => Ok(BencodeRef::ByteString(self.decode_bytestr()?)),
+            None => Err(BdecodingError::MissingTerminator(self.context(self.pos, "bencode element", "end of input")))
+        }?;
+
+        if self.raw_target == Some(self.path.as_slice()) {
+            self.raw_span = Some(&self.input[start..self.pos]);
+        }
+        Ok(ans)
     }
 
-    let e = &e[1..e.len()-1]; // Trim off first and last character.
-    let mut ans = BTreeMap::<Vec::<u8>, BencodeValue>::new();
+    // Parses bytestring of the form: num:XXXXXX
+    fn decode_bytestr(&mut self) -> Result<&'a [u8], BdecodingError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            return Err(BdecodingError::ByteStringError(self.context(start, "non-negative bytestring length", "'-'")));
+        }
+        let len = self.parse_integer(b':')?;
+        let len = match len.to_usize() {
+            Ok(len) if len <= self.options.max_string_length => len,
+            _ => return Err(BdecodingError::StringLengthLimitExceeded(self.context(start, format!("bytestring of at most {} bytes", self.options.max_string_length), format!("length {}", len)))),
+        };
+
+        let remaining = self.input.len() - self.pos;
+        if len > remaining {
+            return Err(BdecodingError::ByteStringError(self.context(self.pos, format!("{} bytes of bytestring content", len), format!("{} bytes until end of input", remaining))));
+        }
 
-    let mut left_index_key = 0;
-    
-    while left_index_key < e.len() {
-        let key_len = get_first_element_len(&e[left_index_key..])?;
-        let left_index_val = left_index_key + key_len;
-        let right_index_val = left_index_val + get_first_element_len(&e[left_index_val..])?;
+        let s = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(s)
+    }
 
-        // Matching with string because we know that bdecode_bytestr allows return a bytestring array instead of list or i64 or dict.
-        let BencodeValue::ByteString(key) = bdecode_bytestr(&e[left_index_key..left_index_val])? else {unreachable!()};
-        let val = bdecode_element(&e[left_index_val..right_index_val])?;
+    // Parses bytestring of the form: iXXXXXe
+    fn decode_integer(&mut self) -> Result<BencodeRef<'a>, BdecodingError> {
+        self.pos += 1; // skip the 'i'.
+        Ok(BencodeRef::Integer(self.parse_integer(b'e')?))
+    }
 
-        if let Some(latest_keyval) = ans.last_entry() {
-            if *latest_keyval.key() >= key {
-                return Err(BdecodingError::DictionaryError(format!("Chaithu: Expected keys to be in strictly increasing lexicographical order.\n{}", String::from_utf8_lossy(&e))));
+    // Parses bytestring of the form: lXXXXXe
+    fn decode_list(&mut self) -> Result<BencodeRef<'a>, BdecodingError> {
+        self.enter_container()?;
+        self.pos += 1; // skip the 'l'.
+
+        let mut ans = Vec::<BencodeRef<'a>>::new();
+        loop {
+            match self.peek() {
+                Some(b'e') => {
+                    self.pos += 1;
+                    self.depth -= 1;
+                    return Ok(BencodeRef::List(ans));
+                }
+                Some(_) => {
+                    self.path.push(PathSegment::Index(ans.len()));
+                    ans.push(self.decode_element()?);
+                    self.path.pop();
+                }
+                None => return Err(BdecodingError::MissingTerminator(self.context(self.pos, "'e' closing the list", "end of input")))
             }
         }
-        ans.insert(key, val);
-        left_index_key = right_index_val;
     }
 
-    Ok(BencodeValue::Dictionary(ans))
+    // Parses bytestring of the form: dXXXXXe
+    fn decode_dictionary(&mut self) -> Result<BencodeRef<'a>, BdecodingError> {
+        self.enter_container()?;
+        self.pos += 1; // skip the 'd'.
+
+        let mut ans = BTreeMap::<&'a [u8], BencodeRef<'a>>::new();
+        loop {
+            match self.peek() {
+                Some(b'e') => {
+                    self.pos += 1;
+                    self.depth -= 1;
+                    return Ok(BencodeRef::Dictionary(ans));
+                }
+                Some(b @ (b'i' | b'l' | b'd')) => {
+                    return Err(BdecodingError::DictionaryError(self.context(self.pos, "bytestring dictionary key", describe_byte(Some(b)))));
+                }
+                Some(_) => {
+                    let key_pos = self.pos;
+                    let key = self.decode_bytestr()?;
+
+                    if let Some((latest_key, _)) = ans.last_key_value()
+                        && *latest_key >= key
+                    {
+                        let err = BdecodingError::DictionaryError(self.context(key_pos, format!("key sorted after {}", describe_bytes(latest_key)), describe_bytes(key)));
+                        let kind = if ans.contains_key(key) { DecodeWarningKind::DuplicateKey } else { DecodeWarningKind::UnsortedKey };
+                        self.tolerate(kind, err)?;
+                    }
+
+                    self.path.push(PathSegment::Key(key));
+                    let val = self.decode_element()?;
+                    self.path.pop();
+                    ans.insert(key, val);
+                }
+                None => return Err(BdecodingError::MissingTerminator(self.context(self.pos, "'e' closing the dictionary", "end of input")))
+            }
+        }
+    }
+}
+
+/// Everything a decode produced besides the value itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded<'a> {
+    pub value: BencodeRef<'a>,
+    /// Raw bytes of the element at the requested path, see `bdecode_detailed`.
+    pub raw: Option<&'a [u8]>,
+    /// Tolerances applied in lenient mode. Always empty in strict mode.
+    pub warnings: Vec<DecodeWarning>,
 }
 
 // parses the entire string as one entity. So you can't have something like i5ei9e to denote 5,9. You have to wrap it in a list.
 pub fn bdecode_element(e: &[u8]) -> Result<BencodeValue, BdecodingError> {
+    bdecode_element_with_options(e, DecodeOptions::default())
+}
+
+pub fn bdecode_element_with_options(e: &[u8], options: DecodeOptions) -> Result<BencodeValue, BdecodingError> {
+    Ok(bdecode_detailed(e, &[], options)?.value.to_value())
+}
+
+// Same as bdecode_element but the result borrows from `e` instead of copying every byte string.
+pub fn bdecode_ref(e: &[u8]) -> Result<BencodeRef<'_>, BdecodingError> {
+    bdecode_ref_with_options(e, DecodeOptions::default())
+}
+
+pub fn bdecode_ref_with_options(e: &[u8], options: DecodeOptions) -> Result<BencodeRef<'_>, BdecodingError> {
+    Ok(bdecode_detailed(e, &[], options)?.value)
+}
+
+// Decodes `e` and also returns the bytes of the element at `path` exactly as they appear in `e` (None if
+// nothing lives at `path`) and any warnings from lenient mode. Hash the raw bytes rather than re-encoding the
+// decoded value: re-encoding only gives back the same bytes when the input was canonical.
+pub fn bdecode_detailed<'a>(e: &'a [u8], path: &[PathSegment], options: DecodeOptions) -> Result<Decoded<'a>, BdecodingError> {
+    let mut decoder = Decoder::new(e, options);
+
+    if e.len() > options.max_input_size {
+        return Err(BdecodingError::InputSizeLimitExceeded(decoder.context(0, format!("input of at most {} bytes", options.max_input_size), format!("{} bytes", e.len()))));
+    }
+
     if e.is_empty() {
-        return Ok(BencodeValue::ByteString(Vec::new()));
+        decoder.tolerate(DecodeWarningKind::EmptyInput, BdecodingError::NullRoot(decoder.context(0, "bencode element", "end of input")))?;
+        return Ok(Decoded { value: BencodeRef::ByteString(&[]), raw: path.is_empty().then_some(e), warnings: decoder.warnings });
     }
 
-    match e[0] {
-        b'i' | b'l' | b'd'  => {
-            if e.last() != Some(&b'e') {
-                return Err(BdecodingError::MissingTerminator(format!("Chaithu: Expected {} to end with 'e' but didn't.\n", String::from_utf8_lossy(&e))));
-            }
+    decoder.raw_target = Some(path);
+    let value = decoder.decode_element()?;
 
-            match e[0] {
-                b'i' => bdecode_i64(&e),
-                b'l' => bdecode_list(&e),
-                b'd' => bdecode_dicitionary(&e),
-                _ => unreachable!()
-            }
-        },
-        _ => bdecode_bytestr(&e),
+    if decoder.pos != e.len() {
+        let found = format!("{} trailing bytes starting with {}", e.len() - decoder.pos, describe_byte(decoder.peek()));
+        let err = BdecodingError::NonSingularRootItem(decoder.context(decoder.pos, "end of input", found));
+        decoder.tolerate(DecodeWarningKind::TrailingBytes, err)?;
     }
+
+    Ok(Decoded { value, raw: decoder.raw_span, warnings: decoder.warnings })
+}
+
+// Returns the raw bytes of the element at `path`, eg. &[PathSegment::Key(b"info")] for a torrent's info dictionary.
+pub fn bdecode_raw<'a>(e: &'a [u8], path: &[PathSegment], options: DecodeOptions) -> Result<Option<&'a [u8]>, BdecodingError> {
+    Ok(bdecode_detailed(e, path, options)?.raw)
 }
 
 #[cfg(test)]
@@ -223,9 +485,17 @@ mod tests {
 
     #[test]
     fn test_valid_integers() {
-        assert_eq!(bdecode_element(b"i0e").unwrap(), BencodeValue::Integer(0));
-        assert_eq!(bdecode_element(b"i42e").unwrap(), BencodeValue::Integer(42));
-        assert_eq!(bdecode_element(b"i-5e").unwrap(), BencodeValue::Integer(-5));
+        assert_eq!(bdecode_element(b"i0e").unwrap(), BencodeValue::Integer(0.into()));
+        assert_eq!(bdecode_element(b"i42e").unwrap(), BencodeValue::Integer(42.into()));
+        assert_eq!(bdecode_element(b"i-5e").unwrap(), BencodeValue::Integer((-5).into()));
+    }
+
+    #[test]
+    fn test_big_integers() {
+        let encoded = b"i-123456789012345678901234567890e";
+        let decoded = bdecode_element(encoded).unwrap();
+        assert_eq!(decoded, BencodeValue::Integer(BencodeInt::parse("-123456789012345678901234567890").unwrap()));
+        assert_eq!(bencode_element(&decoded), encoded.to_vec());
     }
 
     #[test]
@@ -271,13 +541,13 @@ mod tests {
         assert_eq!(bdecode_element(b"le").unwrap(), BencodeValue::List(vec![]));
         assert_eq!(
             bdecode_element(b"li42ee").unwrap(),
-            BencodeValue::List(vec![BencodeValue::Integer(42)])
+            BencodeValue::List(vec![BencodeValue::Integer(42.into())])
         );
         assert_eq!(
             bdecode_element(b"l4:spami42ee").unwrap(),
             BencodeValue::List(vec![
                 BencodeValue::ByteString(b"spam".to_vec()),
-                BencodeValue::Integer(42)
+                BencodeValue::Integer(42.into())
             ])
         );
         assert_eq!(
@@ -320,7 +590,8 @@ mod tests {
         assert!(res.is_err());
 
         match res {
-            Err(BdecodingError::IntegerError(msg)) => {
+            Err(err @ BdecodingError::IntegerError(_)) => {
+                let msg = err.to_string();
                 // message may vary; check it mentions UTF-8 or integer parsing
                 assert!(
                     msg.contains("UTF-8") || msg.contains("integer"),
@@ -341,8 +612,9 @@ mod tests {
 
     #[test]
     fn test_empty_input() {
+        assert!(matches!(bdecode_element(b""), Err(BdecodingError::NullRoot(_))));
         assert_eq!(
-            bdecode_element(b"").unwrap(),
+            bdecode_element_with_options(b"", DecodeOptions::lenient()).unwrap(),
             BencodeValue::ByteString(Vec::new())
         );
     }
@@ -369,12 +641,193 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_deeply_nested_lists() {
+        let depth = 500;
+        let mut encoded = vec![b'l'; depth];
+        encoded.extend_from_slice(b"i0e");
+        encoded.extend(vec![b'e'; depth]);
+
+        let mut expected = BencodeValue::Integer(0.into());
+        for _ in 0..depth {
+            expected = BencodeValue::List(vec![expected]);
+        }
+        assert_eq!(bdecode_element(&encoded).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        assert!(bdecode_element(b"4:spamXYZ").is_err());
+        assert!(bdecode_element(b"lei0e").is_err());
+    }
+
+    #[test]
+    fn test_bdecode_ref_borrows_input() {
+        let encoded = b"d4:spaml1:a1:bee";
+        let decoded = bdecode_ref(encoded).unwrap();
+        let list = decoded.get(b"spam").and_then(BencodeRef::as_list).unwrap();
+        assert_eq!(list, &[BencodeRef::ByteString(b"a"), BencodeRef::ByteString(b"b")]);
+
+        // The byte string points straight into the encoded buffer.
+        let a = list[0].as_bytestring().unwrap();
+        assert!(std::ptr::eq(a.as_ptr(), &encoded[10]));
+    }
+
+    #[test]
+    fn test_error_reports_offset_and_path() {
+        let err = bdecode_element(b"d4:infod5:filesld6:lengthi1eed6:lengthi0x1eeeee").unwrap_err();
+        let ctx = err.context();
+        assert!(matches!(err, BdecodingError::IntegerError(_)));
+        assert_eq!(ctx.offset, 40);
+        assert_eq!(ctx.path, "info.files[1].length");
+        assert_eq!(ctx.expected, "integer digit");
+        assert_eq!(ctx.found, "'x'");
+    }
+
+    #[test]
+    fn test_error_does_not_dump_input() {
+        let mut encoded = b"l".to_vec();
+        encoded.extend(std::iter::repeat_n(b"4:spam".as_slice(), 10_000).flatten());
+        let err = bdecode_element(&encoded).unwrap_err();
+        assert!(matches!(err, BdecodingError::MissingTerminator(_)));
+        assert_eq!(err.offset(), encoded.len());
+        assert!(err.to_string().len() < 200);
+    }
+
+    #[test]
+    fn test_unsorted_key_error() {
+        let err = bdecode_element(b"d3:zoo4:eggs3:foo3:bare").unwrap_err();
+        assert!(matches!(err, BdecodingError::DictionaryError(_)));
+        assert_eq!(err.context().found, "\"foo\"");
+    }
+
+    #[test]
+    fn test_raw_span() {
+        let encoded = b"d8:announce3:url4:infod6:lengthi5e4:name1:ae5:otherl1:x1:yee";
+        assert_eq!(
+            bdecode_raw(encoded, &[PathSegment::Key(b"info")], DecodeOptions::default()).unwrap(),
+            Some(&b"d6:lengthi5e4:name1:ae"[..])
+        );
+        assert_eq!(
+            bdecode_raw(encoded, &[PathSegment::Key(b"other"), PathSegment::Index(1)], DecodeOptions::default()).unwrap(),
+            Some(&b"1:y"[..])
+        );
+        assert_eq!(bdecode_raw(encoded, &[], DecodeOptions::default()).unwrap(), Some(&encoded[..]));
+        assert_eq!(bdecode_raw(encoded, &[PathSegment::Key(b"missing")], DecodeOptions::default()).unwrap(), None);
+    }
+
+    // ------------------ STRICT / LENIENT MODES ------------------
+
+    fn lenient_warnings(e: &[u8]) -> Vec<DecodeWarningKind> {
+        bdecode_detailed(e, &[], DecodeOptions::lenient()).unwrap().warnings.iter().map(|w| w.kind).collect()
+    }
+
+    #[test]
+    fn test_lenient_non_canonical_integers() {
+        for e in [&b"i042e"[..], b"i-042e", b"i00e", b"i-0e"] {
+            assert!(bdecode_element(e).is_err());
+            assert_eq!(lenient_warnings(e), vec![DecodeWarningKind::NonCanonicalInteger]);
+        }
+        assert_eq!(bdecode_element_with_options(b"i-042e", DecodeOptions::lenient()).unwrap(), BencodeValue::Integer((-42).into()));
+        assert_eq!(bdecode_element_with_options(b"04:spam", DecodeOptions::lenient()).unwrap(), BencodeValue::ByteString(b"spam".to_vec()));
+
+        // Lenient mode still rejects things that aren't integers at all.
+        assert!(bdecode_element_with_options(b"iabcde", DecodeOptions::lenient()).is_err());
+        assert!(bdecode_element_with_options(b"i-e", DecodeOptions::lenient()).is_err());
+    }
+
+    #[test]
+    fn test_lenient_dictionary_keys() {
+        let unsorted = b"d3:zoo4:eggs3:foo3:bare";
+        assert!(bdecode_element(unsorted).is_err());
+        assert_eq!(lenient_warnings(unsorted), vec![DecodeWarningKind::UnsortedKey]);
+
+        let duplicate = b"d3:foo3:bar3:foo3:baze";
+        assert!(bdecode_element(duplicate).is_err());
+        assert_eq!(lenient_warnings(duplicate), vec![DecodeWarningKind::DuplicateKey]);
+        assert_eq!(
+            bdecode_element_with_options(duplicate, DecodeOptions::lenient()).unwrap(),
+            BencodeValue::Dictionary(BTreeMap::from([(b"foo".to_vec(), BencodeValue::ByteString(b"baz".to_vec()))]))
+        );
+    }
+
+    #[test]
+    fn test_lenient_root() {
+        assert_eq!(lenient_warnings(b""), vec![DecodeWarningKind::EmptyInput]);
+        assert_eq!(lenient_warnings(b"i5ei9e"), vec![DecodeWarningKind::TrailingBytes]);
+        assert_eq!(bdecode_element_with_options(b"i5ei9e", DecodeOptions::lenient()).unwrap(), BencodeValue::Integer(5.into()));
+        assert!(lenient_warnings(b"d3:cow3:mooe").is_empty());
+    }
+
+    #[test]
+    fn test_raw_span_of_non_canonical_info() {
+        // Re-encoding this info dict would sort the keys and drop the leading zero, giving a different info hash.
+        let encoded = b"d4:infod4:name1:a6:lengthi05eee";
+        assert!(bdecode_raw(encoded, &[PathSegment::Key(b"info")], DecodeOptions::default()).is_err());
+        assert_eq!(
+            bdecode_raw(encoded, &[PathSegment::Key(b"info")], DecodeOptions::lenient()).unwrap(),
+            Some(&b"d4:name1:a6:lengthi05ee"[..])
+        );
+    }
+
+    // ------------------ RESOURCE LIMITS ------------------
+
+    #[test]
+    fn test_depth_limit() {
+        let options = DecodeOptions { max_depth: 3, ..DecodeOptions::default() };
+        assert!(bdecode_element_with_options(b"llleee", options).is_ok());
+        assert!(bdecode_element_with_options(b"lldeee", options).is_ok());
+
+        let err = bdecode_element_with_options(b"lllleeee", options).unwrap_err();
+        assert!(matches!(err, BdecodingError::DepthLimitExceeded(_)));
+        assert_eq!(err.offset(), 3);
+
+        // A hostile peer can't blow the stack with the default options either.
+        let encoded = vec![b'l'; 1_000_000];
+        assert!(matches!(bdecode_element(&encoded), Err(BdecodingError::DepthLimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_item_limit() {
+        let options = DecodeOptions { max_items: 3, ..DecodeOptions::default() };
+        assert!(bdecode_element_with_options(b"li1ei2ee", options).is_ok());
+        assert!(matches!(
+            bdecode_element_with_options(b"li1ei2ei3ee", options),
+            Err(BdecodingError::ItemLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_string_length_limit() {
+        let options = DecodeOptions { max_string_length: 4, ..DecodeOptions::default() };
+        assert!(bdecode_element_with_options(b"4:spam", options).is_ok());
+        assert!(matches!(
+            bdecode_element_with_options(b"5:spams", options),
+            Err(BdecodingError::StringLengthLimitExceeded(_))
+        ));
+        // The claimed length is rejected before looking at whether the input is that long.
+        assert!(matches!(
+            bdecode_element_with_options(b"99999999999:x", options),
+            Err(BdecodingError::StringLengthLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_input_size_limit() {
+        let options = DecodeOptions { max_input_size: 5, ..DecodeOptions::default() };
+        assert!(bdecode_element_with_options(b"3:abc", options).is_ok());
+        assert!(matches!(
+            bdecode_element_with_options(b"4:spam", options),
+            Err(BdecodingError::InputSizeLimitExceeded(_))
+        ));
+    }
+
     // ------------------ ROUND-TRIP ENCODE/DECODE ------------------
 
     #[test]
     fn test_round_trip() {
         let original = BencodeValue::List(vec![
-            BencodeValue::Integer(42),
+            BencodeValue::Integer(42.into()),
             BencodeValue::ByteString(b"spam".to_vec()),
         ]);
         let encoded = bencode_element(&original);
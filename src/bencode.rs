@@ -1,25 +1,104 @@
 #![allow(dead_code)]
 #[allow(unused)]
-
 use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Debug;
+use std::io::{self, Write};
+
+use crate::bencode_int::BencodeInt;
 
 /// Public enum that callers will use
 #[derive(Clone, PartialEq, Eq)]
 pub enum BencodeValue {
-    Integer(i64),
+    Integer(BencodeInt),
     ByteString(Vec<u8>),
     List(Vec<BencodeValue>),
     Dictionary(BTreeMap<Vec<u8>, BencodeValue>),
 }
 
+/// Borrowed counterpart of `BencodeValue`. Byte strings and dictionary keys point into the buffer
+/// that was decoded, so nothing is copied until `to_value` is called.
+#[derive(Clone, PartialEq, Eq)]
+pub enum BencodeRef<'a> {
+    Integer(BencodeInt),
+    ByteString(&'a [u8]),
+    List(Vec<BencodeRef<'a>>),
+    Dictionary(BTreeMap<&'a [u8], BencodeRef<'a>>),
+}
+
+impl<'a> BencodeRef<'a> {
+    pub fn as_integer(&self) -> Option<&BencodeInt> {
+        match self {
+            BencodeRef::Integer(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytestring(&self) -> Option<&'a [u8]> {
+        match self {
+            BencodeRef::ByteString(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeRef<'a>]> {
+        match self {
+            BencodeRef::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dictionary(&self) -> Option<&BTreeMap<&'a [u8], BencodeRef<'a>>> {
+        match self {
+            BencodeRef::Dictionary(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeRef<'a>> {
+        self.as_dictionary()?.get(key)
+    }
+
+    /// Copies the borrowed tree into an owned `BencodeValue`.
+    pub fn to_value(&self) -> BencodeValue {
+        match self {
+            BencodeRef::Integer(i) => BencodeValue::Integer(i.clone()),
+            BencodeRef::ByteString(s) => BencodeValue::ByteString(s.to_vec()),
+            BencodeRef::List(l) => BencodeValue::List(l.iter().map(BencodeRef::to_value).collect()),
+            BencodeRef::Dictionary(d) => BencodeValue::Dictionary(
+                d.iter().map(|(k, v)| (k.to_vec(), v.to_value())).collect()
+            ),
+        }
+    }
+}
+
+impl<'a> From<BencodeRef<'a>> for BencodeValue {
+    fn from(b: BencodeRef<'a>) -> Self {
+        b.to_value()
+    }
+}
+
+impl BencodeValue {
+    /// Borrows this value as a `BencodeRef` without copying any byte strings.
+    pub fn as_bencode_ref(&self) -> BencodeRef<'_> {
+        match self {
+            BencodeValue::Integer(i) => BencodeRef::Integer(i.clone()),
+            BencodeValue::ByteString(s) => BencodeRef::ByteString(s),
+            BencodeValue::List(l) => BencodeRef::List(l.iter().map(BencodeValue::as_bencode_ref).collect()),
+            BencodeValue::Dictionary(d) => BencodeRef::Dictionary(
+                d.iter().map(|(k, v)| (k.as_slice(), v.as_bencode_ref())).collect()
+            ),
+        }
+    }
+}
+
 pub fn get_dictionary(b: &BencodeValue) -> Result<BTreeMap<Vec<u8>, BencodeValue>, Box<dyn std::error::Error>> {
     let BencodeValue::Dictionary(dict) = b else {
         eprintln!("ERROR: Expected bencode value to be a dicitionary");
         return Err(format!("bencode_value = {b:#?}").into());
     };
-    return Ok(dict.clone());
+    Ok(dict.clone())
 }
 
 pub fn get_list(b: &BencodeValue) -> Result<Vec<BencodeValue>, Box<dyn std::error::Error>> {
@@ -27,16 +106,16 @@ pub fn get_list(b: &BencodeValue) -> Result<Vec<BencodeValue>, Box<dyn std::erro
         eprintln!("ERROR: Expected bencode value to be a list");
         return Err(format!("bencode_value = {b:#?}").into());
     };
-    return Ok(vec.clone());
+    Ok(vec.clone())
 }
 
 
-pub fn get_integer(b: &BencodeValue) -> Result<i64, Box<dyn std::error::Error>> {
+pub fn get_integer(b: &BencodeValue) -> Result<BencodeInt, Box<dyn std::error::Error>> {
     let BencodeValue::Integer(i) = b else {
         eprintln!("ERROR: Expected bencode value to be an integer.");
         return Err(format!("bencode_value = {b:#?}").into());
     };
-    return Ok(i.clone());
+    Ok(i.clone())
 }
 
 pub fn get_bytestring(b: &BencodeValue) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -44,12 +123,12 @@ pub fn get_bytestring(b: &BencodeValue) -> Result<Vec<u8>, Box<dyn std::error::E
         eprintln!("ERROR: Expected bencode value to be a bytestring.");
         return Err(format!("bencode_value = {b:#?}").into());
     };
-    return Ok(s.clone());
+    Ok(s.clone())
 }
 
 pub fn get_utf8_lossy(b: &BencodeValue) -> Result<String, Box<dyn std::error::Error>> {
     let s = get_bytestring(b)?;
-    return Ok(String::from_utf8_lossy(&s).to_string());
+    Ok(String::from_utf8_lossy(&s).to_string())
 }
 // pub fn get_utf8_lossy(b: &BencodeValue) -> 
 
@@ -80,43 +159,215 @@ impl Debug for BencodeValue {
     }
 }
 
+impl Debug for BencodeRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BencodeRef::Integer(i) => write!(f, "Integer({})", i),
+            BencodeRef::ByteString(bytes) => write!(f, "ByteString(\"{}\")", String::from_utf8_lossy(bytes)),
+            BencodeRef::List(list) => {
+                write!(f, "List(")?;
+                f.debug_list().entries(list).finish()?;
+                write!(f, ")")
+            }
+            BencodeRef::Dictionary(map) => {
+                write!(f, "Dictionary(")?;
+                f.debug_map()
+                    .entries(map.iter().map(|(k, v)| {
+                        (String::from_utf8_lossy(k), v)
+                    }))
+                    .finish()?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
 /// Public entry-point encoder
 pub fn bencode_element(b: &BencodeValue) -> Vec<u8> {
+    let mut ans = Vec::new();
+    // Writing into a Vec never fails.
+    bencode_to_writer(&mut ans, b).unwrap();
+    ans
+}
+
+/// Encodes `b` straight into `w`, without building a buffer for every nested list and dictionary.
+pub fn bencode_to_writer<W: Write + ?Sized>(w: &mut W, b: &BencodeValue) -> io::Result<()> {
     match b {
-        BencodeValue::Integer(i)    => bencode_i64(*i),
-        BencodeValue::ByteString(s) => bencode_bytestr(s),
-        BencodeValue::List(l)       => bencode_list(l),
-        BencodeValue::Dictionary(d) => bencode_dict(d),
+        BencodeValue::Integer(i)    => write_integer(w, i),
+        BencodeValue::ByteString(s) => write_bytestr(w, s),
+        BencodeValue::List(l)       => {
+            w.write_all(b"l")?;
+            for e in l {
+                bencode_to_writer(w, e)?;
+            }
+            w.write_all(b"e")
+        }
+        BencodeValue::Dictionary(d) => {
+            w.write_all(b"d")?;
+            for (key, val) in d {
+                write_bytestr(w, key)?;
+                bencode_to_writer(w, val)?;
+            }
+            w.write_all(b"e")
+        }
     }
 }
 
-fn bencode_i64(a: i64) -> Vec<u8> {
-    ("i".to_owned() + &a.to_string() + "e").into_bytes()
+fn write_integer<W: Write + ?Sized>(w: &mut W, a: &BencodeInt) -> io::Result<()> {
+    write!(w, "i{}e", a)
 }
 
-fn bencode_bytestr(s: &Vec<u8>) -> Vec<u8> {
-    let mut ans = (s.len().to_string() + ":").into_bytes();
-    ans.extend(s);
-    ans
+fn write_bytestr<W: Write + ?Sized>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    write!(w, "{}:", s.len())?;
+    w.write_all(s)
+}
+
+enum Frame {
+    List,
+    // `last_key` is kept to check that keys are written in sorted order.
+    Dictionary { last_key: Option<Vec<u8>>, awaiting_value: bool },
 }
 
-fn bencode_list(v: &Vec<BencodeValue>) -> Vec<u8> {
-    let mut ans = b"l".to_vec();
-    for e in v {
-        ans.append(&mut bencode_element(&e));
+/// Incremental encoder for emitting lists and dictionaries piece by piece, eg. a .torrent whose
+/// `pieces` string is streamed in without holding the whole file in memory.
+///
+/// Exactly one top-level value is written. Inside a dictionary every value must be preceded by `key`,
+/// and keys must be strictly increasing. Misuse is reported as an `io::ErrorKind::InvalidInput` error rather than producing invalid bencode.
+pub struct BencodeWriter<W: Write> {
+    w: W,
+    stack: Vec<Frame>,
+    // Set once the top-level value was started; anything after it would not be valid bencode.
+    root_written: bool,
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+impl<W: Write> BencodeWriter<W> {
+    pub fn new(w: W) -> Self {
+        BencodeWriter { w, stack: Vec::new(), root_written: false }
     }
-    ans.push(b'e');
-    ans
+
+    // Called before writing any value. Checks a dictionary value has a key in front of it, and that there is
+    // only one top-level value.
+    fn before_value(&mut self) -> io::Result<()> {
+        match self.stack.last_mut() {
+            None => {
+                if self.root_written {
+                    return Err(invalid_input("Only one top-level value can be written."));
+                }
+                self.root_written = true;
+            }
+            Some(Frame::Dictionary { awaiting_value, .. }) => {
+                if !*awaiting_value {
+                    return Err(invalid_input("Dictionary value written without a key."));
+                }
+                *awaiting_value = false;
+            }
+            Some(Frame::List) => {}
+        }
+        Ok(())
+    }
+
+    pub fn integer<I: Into<BencodeInt>>(&mut self, i: I) -> io::Result<()> {
+        self.before_value()?;
+        write_integer(&mut self.w, &i.into())
+    }
+
+    pub fn bytestring(&mut self, s: &[u8]) -> io::Result<()> {
+        self.before_value()?;
+        write_bytestr(&mut self.w, s)
+    }
+
+    /// Writes a byte string whose content is produced by `f` in chunks. `f` must write exactly `len` bytes.
+    pub fn bytestring_with<F>(&mut self, len: usize, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        self.before_value()?;
+        write!(self.w, "{}:", len)?;
+        let mut counter = CountingWriter { w: &mut self.w, count: 0 };
+        f(&mut counter)?;
+        if counter.count != len {
+            return Err(invalid_input("Byte string content does not match its declared length."));
+        }
+        Ok(())
+    }
+
+    /// Writes a whole value at once.
+    pub fn value(&mut self, b: &BencodeValue) -> io::Result<()> {
+        self.before_value()?;
+        bencode_to_writer(&mut self.w, b)
+    }
+
+    pub fn begin_list(&mut self) -> io::Result<()> {
+        self.before_value()?;
+        self.w.write_all(b"l")?;
+        self.stack.push(Frame::List);
+        Ok(())
+    }
+
+    pub fn begin_dictionary(&mut self) -> io::Result<()> {
+        self.before_value()?;
+        self.w.write_all(b"d")?;
+        self.stack.push(Frame::Dictionary { last_key: None, awaiting_value: false });
+        Ok(())
+    }
+
+    pub fn key(&mut self, key: &[u8]) -> io::Result<()> {
+        let Some(Frame::Dictionary { last_key, awaiting_value }) = self.stack.last_mut() else {
+            return Err(invalid_input("Key written outside of a dictionary."));
+        };
+        if *awaiting_value {
+            return Err(invalid_input("Key written where a dictionary value was expected."));
+        }
+        if last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(invalid_input("Dictionary keys must be written in strictly increasing order."));
+        }
+        *last_key = Some(key.to_vec());
+        *awaiting_value = true;
+        write_bytestr(&mut self.w, key)
+    }
+
+    /// Closes the innermost open list or dictionary.
+    pub fn end(&mut self) -> io::Result<()> {
+        match self.stack.pop() {
+            None => Err(invalid_input("end called with no open list or dictionary.")),
+            Some(Frame::Dictionary { awaiting_value: true, .. }) => Err(invalid_input("Dictionary closed after a key without a value.")),
+            Some(_) => self.w.write_all(b"e"),
+        }
+    }
+
+    /// Flushes and returns the underlying writer. Fails if nothing was written or a list or dictionary is
+    /// still open.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.root_written {
+            return Err(invalid_input("BencodeWriter finished without writing a value."));
+        }
+        if !self.stack.is_empty() {
+            return Err(invalid_input("BencodeWriter finished with an open list or dictionary."));
+        }
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+struct CountingWriter<'w, W: Write> {
+    w: &'w mut W,
+    count: usize,
 }
 
-fn bencode_dict(d: &BTreeMap<Vec<u8>, BencodeValue>) -> Vec<u8> {
-    let mut ans = b"d".to_vec();
-    for (key, val) in d {
-        ans.append(&mut bencode_bytestr(&key));
-        ans.append(&mut bencode_element(&val));
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
     }
-    ans.push(b'e');
-    ans
 }
 
 #[cfg(test)]
@@ -126,7 +377,7 @@ mod tests {
     #[test]
     fn encode_integer() {
         assert_eq!(
-            bencode_element(&BencodeValue::Integer(42)),
+            bencode_element(&BencodeValue::Integer(42.into())),
             b"i42e".to_vec()
         );
     }
@@ -144,9 +395,85 @@ mod tests {
         assert_eq!(
             bencode_element(&BencodeValue::List(vec![
                 BencodeValue::ByteString(b"spam".to_vec()),
-                BencodeValue::Integer(42),
+                BencodeValue::Integer(42.into()),
             ])),
             b"l4:spami42ee".to_vec()
         );
     }
+
+    #[test]
+    fn encode_big_integer() {
+        let big = BencodeInt::parse("123456789012345678901234567890").unwrap();
+        assert_eq!(
+            bencode_element(&BencodeValue::Integer(big)),
+            b"i123456789012345678901234567890e".to_vec()
+        );
+    }
+
+    #[test]
+    fn writer_matches_bencode_element() {
+        let value = BencodeValue::Dictionary(BTreeMap::from([
+            (b"a".to_vec(), BencodeValue::Integer((-3).into())),
+            (b"list".to_vec(), BencodeValue::List(vec![
+                BencodeValue::ByteString(b"spam".to_vec()),
+                BencodeValue::List(vec![]),
+            ])),
+        ]));
+
+        let mut w = BencodeWriter::new(Vec::new());
+        w.begin_dictionary().unwrap();
+        w.key(b"a").unwrap();
+        w.integer(-3).unwrap();
+        w.key(b"list").unwrap();
+        w.begin_list().unwrap();
+        w.bytestring_with(4, |out| {
+            out.write_all(b"sp")?;
+            out.write_all(b"am")
+        }).unwrap();
+        w.value(&BencodeValue::List(vec![])).unwrap();
+        w.end().unwrap();
+        w.end().unwrap();
+
+        assert_eq!(w.finish().unwrap(), bencode_element(&value));
+    }
+
+    #[test]
+    fn writer_rejects_misuse() {
+        let mut w = BencodeWriter::new(Vec::new());
+        w.begin_dictionary().unwrap();
+        assert!(w.integer(1).is_err()); // value without a key
+        w.key(b"b").unwrap();
+        assert!(w.key(b"c").is_err()); // key where a value was expected
+        w.integer(1).unwrap();
+        assert!(w.key(b"a").is_err()); // unsorted key
+
+        let mut w = BencodeWriter::new(Vec::new());
+        assert!(w.bytestring_with(3, |out| out.write_all(b"ab")).is_err()); // short content
+
+        let mut w = BencodeWriter::new(Vec::new());
+        w.begin_list().unwrap();
+        assert!(w.key(b"a").is_err()); // key inside a list
+        assert!(w.finish().is_err()); // list left open
+
+        let mut w = BencodeWriter::new(Vec::new());
+        w.integer(1).unwrap();
+        assert!(w.integer(2).is_err()); // second top-level value
+        assert!(w.begin_list().is_err());
+        assert_eq!(w.finish().unwrap(), b"i1e");
+
+        assert!(BencodeWriter::new(Vec::new()).finish().is_err()); // nothing written
+    }
+
+    #[test]
+    fn ref_to_value_and_back() {
+        let value = BencodeValue::Dictionary(BTreeMap::from([
+            (b"list".to_vec(), BencodeValue::List(vec![
+                BencodeValue::ByteString(b"spam".to_vec()),
+                BencodeValue::Integer(42.into()),
+            ])),
+        ]));
+        let borrowed = value.as_bencode_ref();
+        assert_eq!(borrowed.get(b"list").and_then(BencodeRef::as_list).map(|l| l.len()), Some(2));
+        assert_eq!(borrowed.to_value(), value);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,211 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+/// A bencode integer of any size. The spec puts no bound on integers, so anything that doesn't fit in an
+/// i64 keeps its decimal digits and is written back out exactly as it was read.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct BencodeInt(Repr);
+
+// Invariant: `Big` is only used when the value does not fit in an i64, and `digits` never has a leading zero.
+// That keeps equality on the derived impls meaningful.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Repr {
+    Small(i64),
+    Big { negative: bool, digits: Box<str> },
+}
+
+/// Returned when a `BencodeInt` does not fit in the integer type it is converted to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegerConversionError {
+    pub value: BencodeInt,
+    pub target: &'static str,
+}
+
+impl fmt::Display for IntegerConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "integer {} does not fit in {}", self.value, self.target)
+    }
+}
+
+impl std::error::Error for IntegerConversionError {}
+
+impl BencodeInt {
+    /// Parses an optional '-' followed by ASCII digits. Leading zeros and -0 are normalized away,
+    /// so whether the text was canonical has to be checked by the caller.
+    pub fn parse(text: &str) -> Option<BencodeInt> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let digits = digits.trim_start_matches('0');
+        if digits.is_empty() {
+            return Some(BencodeInt(Repr::Small(0)));
+        }
+
+        let signed = if negative { format!("-{}", digits) } else { digits.to_string() };
+        match signed.parse::<i64>() {
+            Ok(i) => Some(BencodeInt(Repr::Small(i))),
+            Err(_) => Some(BencodeInt(Repr::Big { negative, digits: digits.into() })),
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match &self.0 {
+            Repr::Small(i) => *i < 0,
+            Repr::Big { negative, .. } => *negative,
+        }
+    }
+
+    fn conversion_error(&self, target: &'static str) -> IntegerConversionError {
+        IntegerConversionError { value: self.clone(), target }
+    }
+
+    pub fn to_i64(&self) -> Result<i64, IntegerConversionError> {
+        match &self.0 {
+            Repr::Small(i) => Ok(*i),
+            Repr::Big { .. } => Err(self.conversion_error("i64")),
+        }
+    }
+
+    pub fn to_i128(&self) -> Result<i128, IntegerConversionError> {
+        match &self.0 {
+            Repr::Small(i) => Ok(*i as i128),
+            Repr::Big { .. } => self.to_string().parse().map_err(|_| self.conversion_error("i128")),
+        }
+    }
+
+    pub fn to_u128(&self) -> Result<u128, IntegerConversionError> {
+        match &self.0 {
+            Repr::Small(i) => u128::try_from(*i).map_err(|_| self.conversion_error("u128")),
+            Repr::Big { negative: false, digits } => digits.parse().map_err(|_| self.conversion_error("u128")),
+            Repr::Big { negative: true, .. } => Err(self.conversion_error("u128")),
+        }
+    }
+
+    pub fn to_u64(&self) -> Result<u64, IntegerConversionError> {
+        self.to_u128()
+            .ok()
+            .and_then(|i| u64::try_from(i).ok())
+            .ok_or_else(|| self.conversion_error("u64"))
+    }
+
+    pub fn to_u32(&self) -> Result<u32, IntegerConversionError> {
+        self.to_i64()
+            .ok()
+            .and_then(|i| u32::try_from(i).ok())
+            .ok_or_else(|| self.conversion_error("u32"))
+    }
+
+    pub fn to_u16(&self) -> Result<u16, IntegerConversionError> {
+        self.to_i64()
+            .ok()
+            .and_then(|i| u16::try_from(i).ok())
+            .ok_or_else(|| self.conversion_error("u16"))
+    }
+
+    pub fn to_usize(&self) -> Result<usize, IntegerConversionError> {
+        self.to_u64()
+            .ok()
+            .and_then(|i| usize::try_from(i).ok())
+            .ok_or_else(|| self.conversion_error("usize"))
+    }
+}
+
+macro_rules! impl_from_small {
+    ($($t:ty),*) => {
+        $(impl From<$t> for BencodeInt {
+            fn from(i: $t) -> Self {
+                BencodeInt(Repr::Small(i as i64))
+            }
+        })*
+    };
+}
+
+impl_from_small!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! impl_from_large {
+    ($($t:ty),*) => {
+        $(impl From<$t> for BencodeInt {
+            fn from(i: $t) -> Self {
+                match i64::try_from(i) {
+                    Ok(i) => BencodeInt(Repr::Small(i)),
+                    Err(_) => BencodeInt::parse(&i.to_string()).unwrap(),
+                }
+            }
+        })*
+    };
+}
+
+impl_from_large!(u64, usize, i128, u128);
+
+impl fmt::Display for BencodeInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Repr::Small(i) => write!(f, "{}", i),
+            Repr::Big { negative: true, digits } => write!(f, "-{}", digits),
+            Repr::Big { negative: false, digits } => write!(f, "{}", digits),
+        }
+    }
+}
+
+impl fmt::Debug for BencodeInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_normalizes() {
+        assert_eq!(BencodeInt::parse("42"), Some(BencodeInt::from(42)));
+        assert_eq!(BencodeInt::parse("-042"), Some(BencodeInt::from(-42)));
+        assert_eq!(BencodeInt::parse("-0"), Some(BencodeInt::from(0)));
+        assert_eq!(BencodeInt::parse("000"), Some(BencodeInt::from(0)));
+        assert_eq!(BencodeInt::parse(""), None);
+        assert_eq!(BencodeInt::parse("-"), None);
+        assert_eq!(BencodeInt::parse("+5"), None);
+        assert_eq!(BencodeInt::parse("1a"), None);
+    }
+
+    #[test]
+    fn big_integers_round_trip() {
+        let text = "-123456789012345678901234567890";
+        let big = BencodeInt::parse(text).unwrap();
+        assert_eq!(big.to_string(), text);
+        assert!(big.is_negative());
+        assert!(big.to_i64().is_err());
+        assert!(big.to_i128().is_ok());
+        assert_eq!(BencodeInt::parse("00018446744073709551615").unwrap().to_string(), "18446744073709551615");
+    }
+
+    #[test]
+    fn from_and_equality() {
+        assert_eq!(BencodeInt::from(u64::MAX), BencodeInt::parse("18446744073709551615").unwrap());
+        assert_eq!(BencodeInt::from(7u64), BencodeInt::from(7i8));
+        assert_eq!(BencodeInt::from(i64::MIN).to_i64(), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn checked_conversions() {
+        let port = BencodeInt::from(6881);
+        assert_eq!(port.to_u16(), Ok(6881));
+        assert_eq!(port.to_u32(), Ok(6881));
+        assert_eq!(port.to_usize(), Ok(6881));
+
+        let too_big = BencodeInt::from(70000);
+        assert_eq!(too_big.to_u16(), Err(IntegerConversionError { value: too_big.clone(), target: "u16" }));
+        assert!(BencodeInt::from(-1).to_u64().is_err());
+        assert!(BencodeInt::from(-1).to_usize().is_err());
+        assert_eq!(BencodeInt::from(u64::MAX).to_u64(), Ok(u64::MAX));
+        assert!(BencodeInt::parse("18446744073709551616").unwrap().to_u64().is_err());
+        assert_eq!(BencodeInt::from(-1).to_u16().unwrap_err().to_string(), "integer -1 does not fit in u16");
+    }
+}
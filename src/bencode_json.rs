@@ -0,0 +1,164 @@
+#![allow(dead_code)]
+
+// Converts bencode to JSON and back without losing bytes, for inspecting torrents and tracker responses.
+//
+// Byte strings that are valid UTF-8 become JSON strings. Anything else (eg. `pieces` or a binary `peer id`)
+// becomes "hex:" followed by the bytes in hex. A UTF-8 string that itself starts with "hex:" or "int:" is
+// hex-encoded too, so the conversion is always reversible. Integers that don't fit in a JSON number
+// (i64/u64) are written as "int:" followed by their digits. Dictionary keys follow the same rules.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+use crate::bencode::BencodeValue;
+use crate::bencode_int::BencodeInt;
+
+const HEX_PREFIX: &str = "hex:";
+const INT_PREFIX: &str = "int:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    // null, floats and booleans have no bencode equivalent.
+    UnsupportedValue(String),
+    InvalidHex(String),
+    InvalidInteger(String),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::UnsupportedValue(v) => write!(f, "JSON value {} has no bencode equivalent", v),
+            JsonError::InvalidHex(s) => write!(f, "invalid hex byte string {:?}", s),
+            JsonError::InvalidInteger(s) => write!(f, "invalid integer {:?}", s),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn bytes_to_json_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.starts_with(HEX_PREFIX) && !s.starts_with(INT_PREFIX) => s.to_string(),
+        _ => format!("{}{}", HEX_PREFIX, to_hex(bytes)),
+    }
+}
+
+fn json_string_to_bytes(s: &str) -> Result<Vec<u8>, JsonError> {
+    match s.strip_prefix(HEX_PREFIX) {
+        Some(hex) => from_hex(hex).ok_or_else(|| JsonError::InvalidHex(s.to_string())),
+        None => Ok(s.as_bytes().to_vec()),
+    }
+}
+
+pub fn to_json(b: &BencodeValue) -> Value {
+    match b {
+        BencodeValue::Integer(i) => {
+            if let Ok(v) = i.to_i64() {
+                Value::Number(v.into())
+            } else if let Ok(v) = i.to_u64() {
+                Value::Number(v.into())
+            } else {
+                Value::String(format!("{}{}", INT_PREFIX, i))
+            }
+        }
+        BencodeValue::ByteString(s) => Value::String(bytes_to_json_string(s)),
+        BencodeValue::List(l) => Value::Array(l.iter().map(to_json).collect()),
+        BencodeValue::Dictionary(d) => Value::Object(
+            d.iter().map(|(k, v)| (bytes_to_json_string(k), to_json(v))).collect::<Map<String, Value>>()
+        ),
+    }
+}
+
+fn number_to_integer(n: &Number) -> Result<BencodeInt, JsonError> {
+    if let Some(v) = n.as_i64() {
+        Ok(v.into())
+    } else if let Some(v) = n.as_u64() {
+        Ok(v.into())
+    } else {
+        Err(JsonError::UnsupportedValue(n.to_string()))
+    }
+}
+
+pub fn from_json(v: &Value) -> Result<BencodeValue, JsonError> {
+    match v {
+        Value::Number(n) => Ok(BencodeValue::Integer(number_to_integer(n)?)),
+        Value::String(s) => match s.strip_prefix(INT_PREFIX) {
+            Some(digits) => BencodeInt::parse(digits)
+                .map(BencodeValue::Integer)
+                .ok_or_else(|| JsonError::InvalidInteger(s.clone())),
+            None => Ok(BencodeValue::ByteString(json_string_to_bytes(s)?)),
+        },
+        Value::Array(a) => Ok(BencodeValue::List(a.iter().map(from_json).collect::<Result<_, _>>()?)),
+        Value::Object(o) => {
+            let mut dict = BTreeMap::new();
+            for (k, v) in o {
+                dict.insert(json_string_to_bytes(k)?, from_json(v)?);
+            }
+            Ok(BencodeValue::Dictionary(dict))
+        }
+        Value::Null | Value::Bool(_) => Err(JsonError::UnsupportedValue(v.to_string())),
+    }
+}
+
+pub fn to_json_string_pretty(b: &BencodeValue) -> String {
+    // Serializing a serde_json::Value can't fail.
+    serde_json::to_string_pretty(&to_json(b)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn utf8_and_binary_strings() {
+        let value = BencodeValue::Dictionary(BTreeMap::from([
+            (b"name".to_vec(), BencodeValue::ByteString(b"bitcoin.pdf".to_vec())),
+            (b"pieces".to_vec(), BencodeValue::ByteString(vec![0xDE, 0xAD, 0xBE, 0xEF])),
+            (b"length".to_vec(), BencodeValue::Integer(184292.into())),
+        ]));
+        assert_eq!(to_json(&value), json!({
+            "name": "bitcoin.pdf",
+            "pieces": "hex:deadbeef",
+            "length": 184292,
+        }));
+    }
+
+    #[test]
+    fn round_trip_is_exact() {
+        let value = BencodeValue::List(vec![
+            BencodeValue::ByteString(b"hex:not really hex".to_vec()),
+            BencodeValue::ByteString(b"int:5".to_vec()),
+            BencodeValue::ByteString(vec![0xFF, 0x00]),
+            BencodeValue::Integer(BencodeInt::parse("-123456789012345678901234567890").unwrap()),
+            BencodeValue::Integer(u64::MAX.into()),
+            BencodeValue::Dictionary(BTreeMap::from([(vec![0xFF], BencodeValue::Integer(1.into()))])),
+        ]);
+        assert_eq!(from_json(&to_json(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn unsupported_json() {
+        assert!(from_json(&json!(null)).is_err());
+        assert!(from_json(&json!(true)).is_err());
+        assert!(from_json(&json!(1.5)).is_err());
+        assert!(from_json(&json!("hex:abc")).is_err());
+        assert!(from_json(&json!("int:12a")).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+#![allow(dead_code)]
+
+// Path lookups into a decoded BencodeValue or BencodeRef, eg. `torrent.query("info.files[2].path")`, instead of
+// chaining get_dictionary/get_list by hand and cloning at every step.
+//
+// Path syntax: keys are separated by '.', list indices are written as [n]. A key containing '.', '[' or ']'
+// can be written as ["comment.utf-8"].
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bdecode::{PathSegment, format_path};
+use crate::bencode::{BencodeRef, BencodeValue};
+use crate::bencode_int::{BencodeInt, IntegerConversionError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    InvalidPath { path: String, reason: &'static str },
+    // `segment` is the index of the missing key in the path.
+    MissingKey { path: String, segment: usize },
+    IndexOutOfBounds { path: String, len: usize },
+    // `path` names the segment whose value had the wrong type.
+    TypeMismatch { path: String, expected: &'static str, found: &'static str },
+    IntegerOutOfRange { path: String, source: IntegerConversionError },
+    InvalidUtf8 { path: String },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::InvalidPath { path, reason } => write!(f, "invalid path {:?}: {}", path, reason),
+            QueryError::MissingKey { path, .. } => write!(f, "{} is missing", path),
+            QueryError::IndexOutOfBounds { path, len } => write!(f, "{} is out of bounds for a list of length {}", path, len),
+            QueryError::TypeMismatch { path, expected, found } => write!(f, "{} is {}, expected {}", display_path(path), found, expected),
+            QueryError::IntegerOutOfRange { path, source } => write!(f, "{}: {}", display_path(path), source),
+            QueryError::InvalidUtf8 { path } => write!(f, "{} is not valid UTF-8", display_path(path)),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "<root>" } else { path }
+}
+
+/// Splits a path like `info.files[2]["md5sum"]` into segments borrowing from `path`.
+pub fn parse_path(path: &str) -> Result<Vec<PathSegment<'_>>, QueryError> {
+    let invalid = |reason| QueryError::InvalidPath { path: path.to_string(), reason };
+    let bytes = path.as_bytes();
+    let mut segments = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'[' if bytes.get(i + 1) == Some(&b'"') => {
+                let start = i + 2;
+                let len = bytes[start..].iter().position(|b| *b == b'"').ok_or_else(|| invalid("unterminated quoted key"))?;
+                if bytes.get(start + len + 1) != Some(&b']') {
+                    return Err(invalid("expected ']' after quoted key"));
+                }
+                segments.push(PathSegment::Key(&bytes[start..start + len]));
+                i = start + len + 2;
+            }
+            b'[' => {
+                let start = i + 1;
+                let len = bytes[start..].iter().position(|b| *b == b']').ok_or_else(|| invalid("unterminated index"))?;
+                let index = path[start..start + len].parse::<usize>().map_err(|_| invalid("index is not a non-negative integer"))?;
+                segments.push(PathSegment::Index(index));
+                i = start + len + 1;
+            }
+            b'.' => {
+                if i == 0 || i + 1 == bytes.len() || bytes[i + 1] == b'.' {
+                    return Err(invalid("empty key"));
+                }
+                i += 1;
+            }
+            b']' => return Err(invalid("unexpected ']'")),
+            _ if i > 0 && bytes[i - 1] == b']' => return Err(invalid("expected '.' or '[' after ']'")),
+            _ => {
+                let len = bytes[i..].iter().position(|b| matches!(b, b'.' | b'[' | b']')).unwrap_or(bytes.len() - i);
+                segments.push(PathSegment::Key(&bytes[i..i + len]));
+                i += len;
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// Path lookups over a decoded tree. Implemented for the owned `BencodeValue` and for the borrowed `BencodeRef`,
+/// so a torrent or tracker response can be read straight from what the decoder returned without copying it.
+pub trait BencodeQuery: Sized {
+    // What a lookup needs from one node of the tree.
+    fn type_name(&self) -> &'static str;
+    fn integer(&self) -> Option<&BencodeInt>;
+    fn bytes(&self) -> Option<&[u8]>;
+    fn items(&self) -> Option<&[Self]>;
+    // None if this is not a dictionary, Some(None) if it is but has no `key`.
+    fn entry(&self, key: &[u8]) -> Option<Option<&Self>>;
+
+    /// Returns the value at `path`, eg. `info.files[2].path`. The error names the first segment that was
+    /// missing or had the wrong type.
+    fn query(&self, path: &str) -> Result<&Self, QueryError> {
+        self.query_segments(&parse_path(path)?)
+    }
+
+    fn query_segments(&self, path: &[PathSegment]) -> Result<&Self, QueryError> {
+        let mut current = self;
+        for (i, segment) in path.iter().enumerate() {
+            current = match segment {
+                PathSegment::Key(k) => match current.entry(k) {
+                    Some(v) => v.ok_or_else(|| QueryError::MissingKey { path: format_path(&path[..=i]), segment: i })?,
+                    None => {
+                        return Err(QueryError::TypeMismatch { path: format_path(&path[..i]), expected: "a dictionary", found: current.type_name() });
+                    }
+                },
+                PathSegment::Index(n) => match current.items() {
+                    Some(l) => l.get(*n).ok_or_else(|| QueryError::IndexOutOfBounds { path: format_path(&path[..=i]), len: l.len() })?,
+                    None => {
+                        return Err(QueryError::TypeMismatch { path: format_path(&path[..i]), expected: "a list", found: current.type_name() });
+                    }
+                },
+            };
+        }
+        Ok(current)
+    }
+
+    /// Like `query`, but gives Ok(None) if the last key of the path is missing. Used for optional fields.
+    /// Everything before the last key must still be there: `info.missing.x` is an error, not None.
+    fn query_opt(&self, path: &str) -> Result<Option<&Self>, QueryError> {
+        let segments = parse_path(path)?;
+        match self.query_segments(&segments) {
+            Ok(v) => Ok(Some(v)),
+            Err(QueryError::MissingKey { segment, .. }) if segment + 1 == segments.len() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_int_at(&self, path: &str) -> Result<&BencodeInt, QueryError> {
+        let v = self.query(path)?;
+        v.integer().ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "an integer", found: v.type_name() })
+    }
+
+    fn get_bytes_at(&self, path: &str) -> Result<&[u8], QueryError> {
+        let v = self.query(path)?;
+        v.bytes().ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "a bytestring", found: v.type_name() })
+    }
+
+    fn get_str_at(&self, path: &str) -> Result<&str, QueryError> {
+        std::str::from_utf8(self.get_bytes_at(path)?).map_err(|_| QueryError::InvalidUtf8 { path: path.to_string() })
+    }
+
+    fn get_list_at(&self, path: &str) -> Result<&[Self], QueryError> {
+        let v = self.query(path)?;
+        v.items().ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "a list", found: v.type_name() })
+    }
+
+    fn get_i64_at(&self, path: &str) -> Result<i64, QueryError> {
+        let i = self.get_int_at(path)?;
+        i.to_i64().map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+
+    fn get_u64_at(&self, path: &str) -> Result<u64, QueryError> {
+        let i = self.get_int_at(path)?;
+        i.to_u64().map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+
+    fn get_u32_at(&self, path: &str) -> Result<u32, QueryError> {
+        let i = self.get_int_at(path)?;
+        i.to_u32().map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+
+    fn get_u16_at(&self, path: &str) -> Result<u16, QueryError> {
+        let i = self.get_int_at(path)?;
+        i.to_u16().map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+
+    fn get_usize_at(&self, path: &str) -> Result<usize, QueryError> {
+        let i = self.get_int_at(path)?;
+        i.to_usize().map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+
+    // Optional fields: like the getters above, but a missing last key gives Ok(None) (see `query_opt`).
+    // A value that is there but has the wrong type is still an error.
+
+    fn get_opt_int_at(&self, path: &str) -> Result<Option<&BencodeInt>, QueryError> {
+        let Some(v) = self.query_opt(path)? else { return Ok(None) };
+        v.integer().map(Some).ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "an integer", found: v.type_name() })
+    }
+
+    fn get_opt_bytes_at(&self, path: &str) -> Result<Option<&[u8]>, QueryError> {
+        let Some(v) = self.query_opt(path)? else { return Ok(None) };
+        v.bytes().map(Some).ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "a bytestring", found: v.type_name() })
+    }
+
+    fn get_opt_list_at(&self, path: &str) -> Result<Option<&[Self]>, QueryError> {
+        let Some(v) = self.query_opt(path)? else { return Ok(None) };
+        v.items().map(Some).ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "a list", found: v.type_name() })
+    }
+
+    fn get_opt_str_at(&self, path: &str) -> Result<Option<&str>, QueryError> {
+        let Some(s) = self.get_opt_bytes_at(path)? else { return Ok(None) };
+        std::str::from_utf8(s).map(Some).map_err(|_| QueryError::InvalidUtf8 { path: path.to_string() })
+    }
+
+    /// For free-form text that is shown to the user rather than interpreted, like 'comment': invalid UTF-8 is
+    /// replaced instead of being an error.
+    fn get_opt_text_at(&self, path: &str) -> Result<Option<String>, QueryError> {
+        Ok(self.get_opt_bytes_at(path)?.map(|s| String::from_utf8_lossy(s).into_owned()))
+    }
+
+    fn get_opt_i64_at(&self, path: &str) -> Result<Option<i64>, QueryError> {
+        let Some(i) = self.get_opt_int_at(path)? else { return Ok(None) };
+        i.to_i64().map(Some).map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+
+    fn get_opt_u64_at(&self, path: &str) -> Result<Option<u64>, QueryError> {
+        let Some(i) = self.get_opt_int_at(path)? else { return Ok(None) };
+        i.to_u64().map(Some).map_err(|source| QueryError::IntegerOutOfRange { path: path.to_string(), source })
+    }
+}
+
+impl BencodeQuery for BencodeValue {
+    fn type_name(&self) -> &'static str {
+        match self {
+            BencodeValue::Integer(_) => "an integer",
+            BencodeValue::ByteString(_) => "a bytestring",
+            BencodeValue::List(_) => "a list",
+            BencodeValue::Dictionary(_) => "a dictionary",
+        }
+    }
+
+    fn integer(&self) -> Option<&BencodeInt> {
+        if let BencodeValue::Integer(i) = self { Some(i) } else { None }
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        if let BencodeValue::ByteString(s) = self { Some(s) } else { None }
+    }
+
+    fn items(&self) -> Option<&[BencodeValue]> {
+        if let BencodeValue::List(l) = self { Some(l) } else { None }
+    }
+
+    fn entry(&self, key: &[u8]) -> Option<Option<&BencodeValue>> {
+        if let BencodeValue::Dictionary(d) = self { Some(d.get(key)) } else { None }
+    }
+}
+
+impl BencodeQuery for BencodeRef<'_> {
+    fn type_name(&self) -> &'static str {
+        match self {
+            BencodeRef::Integer(_) => "an integer",
+            BencodeRef::ByteString(_) => "a bytestring",
+            BencodeRef::List(_) => "a list",
+            BencodeRef::Dictionary(_) => "a dictionary",
+        }
+    }
+
+    fn integer(&self) -> Option<&BencodeInt> {
+        self.as_integer()
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        self.as_bytestring()
+    }
+
+    fn items(&self) -> Option<&[Self]> {
+        self.as_list()
+    }
+
+    fn entry(&self, key: &[u8]) -> Option<Option<&Self>> {
+        self.as_dictionary().map(|d| d.get(key))
+    }
+}
+
+impl BencodeValue {
+    pub fn get_dict_at(&self, path: &str) -> Result<&BTreeMap<Vec<u8>, BencodeValue>, QueryError> {
+        let v = self.query(path)?;
+        match v {
+            BencodeValue::Dictionary(d) => Ok(d),
+            other => Err(QueryError::TypeMismatch { path: path.to_string(), expected: "a dictionary", found: other.type_name() }),
+        }
+    }
+}
+
+impl<'a> BencodeRef<'a> {
+    pub fn get_dict_at(&self, path: &str) -> Result<&BTreeMap<&'a [u8], BencodeRef<'a>>, QueryError> {
+        let v = self.query(path)?;
+        v.as_dictionary().ok_or_else(|| QueryError::TypeMismatch { path: path.to_string(), expected: "a dictionary", found: v.type_name() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bdecode::{bdecode_element, bdecode_ref};
+
+    fn torrent() -> BencodeValue {
+        bdecode_element(b"d13:comment.utf-82:hi4:infod5:filesld6:lengthi10e4:pathl1:a5:b.txteed6:lengthi-1e4:pathl1:ceee4:name3:diree").unwrap()
+    }
+
+    #[test]
+    fn parse_paths() {
+        assert_eq!(
+            parse_path("info.files[2].path").unwrap(),
+            vec![PathSegment::Key(b"info"), PathSegment::Key(b"files"), PathSegment::Index(2), PathSegment::Key(b"path")]
+        );
+        assert_eq!(parse_path("[\"comment.utf-8\"]").unwrap(), vec![PathSegment::Key(b"comment.utf-8")]);
+        assert_eq!(parse_path("peer id").unwrap(), vec![PathSegment::Key(b"peer id")]);
+        assert_eq!(parse_path("").unwrap(), vec![]);
+        assert!(parse_path("info..files").is_err());
+        assert!(parse_path("files[x]").is_err());
+        assert!(parse_path("files[1").is_err());
+        assert!(parse_path("[\"open").is_err());
+        assert!(parse_path("a[0]b").is_err());
+        assert!(parse_path("a[\"b\"]c").is_err());
+        assert_eq!(parse_path("a[0][1].b").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn typed_getters() {
+        let t = torrent();
+        assert_eq!(t.get_str_at("info.name").unwrap(), "dir");
+        assert_eq!(t.get_u64_at("info.files[0].length").unwrap(), 10);
+        assert_eq!(t.get_bytes_at("info.files[0].path[1]").unwrap(), b"b.txt");
+        assert_eq!(t.get_list_at("info.files").unwrap().len(), 2);
+        assert_eq!(t.get_str_at("[\"comment.utf-8\"]").unwrap(), "hi");
+        assert!(t.query_opt("info.md5sum").unwrap().is_none());
+    }
+
+    #[test]
+    fn optional_getters() {
+        let t = torrent();
+        assert_eq!(t.get_opt_str_at("info.name"), Ok(Some("dir")));
+        assert_eq!(t.get_opt_bytes_at("info.md5sum"), Ok(None));
+        assert_eq!(t.get_opt_list_at("info.files").unwrap().map(<[_]>::len), Some(2));
+        assert_eq!(t.get_opt_list_at("announce-list"), Ok(None));
+        assert_eq!(t.get_opt_text_at("[\"comment.utf-8\"]"), Ok(Some("hi".to_string())));
+        assert_eq!(t.get_opt_u64_at("info.files[0].length"), Ok(Some(10)));
+        assert_eq!(t.get_opt_i64_at("info.files[1].length"), Ok(Some(-1)));
+        assert_eq!(t.get_opt_u64_at("creation date"), Ok(None));
+        assert!(matches!(t.get_opt_u64_at("info.files[1].length"), Err(QueryError::IntegerOutOfRange { .. })));
+        assert!(matches!(t.get_opt_u64_at("info.name"), Err(QueryError::TypeMismatch { .. })));
+        assert!(matches!(t.get_opt_bytes_at("info.missing.x"), Err(QueryError::MissingKey { .. })));
+    }
+
+    #[test]
+    fn queries_borrowed_tree() {
+        let bytes = b"d4:infod5:filesld6:lengthi10e4:pathl1:aeee6:pieces3:abcee";
+        let t = bdecode_ref(bytes).unwrap();
+        assert_eq!(t.get_bytes_at("info.pieces").unwrap(), b"abc");
+        assert_eq!(t.get_u64_at("info.files[0].length").unwrap(), 10);
+        assert_eq!(t.get_dict_at("info.files[0]").unwrap().len(), 2);
+        assert_eq!(t.query_opt("info.files[0].md5sum"), Ok(None));
+        assert_eq!(
+            t.get_list_at("info.pieces"),
+            Err(QueryError::TypeMismatch { path: "info.pieces".to_string(), expected: "a list", found: "a bytestring" })
+        );
+    }
+
+    #[test]
+    fn errors_name_the_segment() {
+        let t = torrent();
+        assert_eq!(t.query("info.files[0].md5sum"), Err(QueryError::MissingKey { path: "info.files[0].md5sum".to_string(), segment: 3 }));
+        assert_eq!(t.query("info.files[5]"), Err(QueryError::IndexOutOfBounds { path: "info.files[5]".to_string(), len: 2 }));
+        assert_eq!(
+            t.query("info.name.first"),
+            Err(QueryError::TypeMismatch { path: "info.name".to_string(), expected: "a dictionary", found: "a bytestring" })
+        );
+        assert_eq!(
+            t.get_u64_at("info.files").unwrap_err().to_string(),
+            "info.files is a list, expected an integer"
+        );
+        assert!(matches!(t.get_u64_at("info.files[1].length"), Err(QueryError::IntegerOutOfRange { .. })));
+        // query_opt only turns a missing last key into None. A missing key earlier in the path, or a value of
+        // the wrong type, is still an error.
+        assert_eq!(t.query_opt("info.files[0].md5sum"), Ok(None));
+        assert_eq!(t.query_opt("info.missing.x"), Err(QueryError::MissingKey { path: "info.missing".to_string(), segment: 1 }));
+        // Keys are compared as segments, not as formatted paths: "a.b" as one quoted key is not the path a.b.
+        let dotted = bdecode_element(b"d1:ad1:bi1eee").unwrap();
+        assert_eq!(dotted.query_opt("[\"a.b\"]"), Ok(None));
+        assert!(dotted.query_opt("[\"a.b\"].c").is_err());
+        assert!(t.query_opt("info.name.first").is_err());
+    }
+}
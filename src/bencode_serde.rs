@@ -0,0 +1,623 @@
+#![allow(dead_code)]
+
+// serde data format for bencode, so torrents, tracker responses and KRPC messages can be read into
+// `#[derive(Deserialize)]` structs instead of walking BencodeValue by hand.
+//
+// Mapping:
+// - integers of any width (including i128/u128) and bools are bencode integers (bools as 0/1). Floats are not supported.
+// - strings, chars and bytes (use serde_bytes for Vec<u8> fields) are bencode byte strings.
+// - sequences and tuples are lists.
+// - maps and structs are dictionaries. Keys are sorted when serializing because they go through a BTreeMap.
+// - None fields are left out of the dictionary. A missing field deserializes to None.
+// - unit variants are byte strings holding the variant name, other variants are {name: value}.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use serde::{Deserialize, forward_to_deserialize_any};
+
+use crate::bdecode::{BdecodingError, bdecode_ref};
+use crate::bencode::{BencodeRef, BencodeValue, bencode_element, bencode_to_writer};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerdeError {
+    Message(String),
+    Decode(BdecodingError),
+}
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerdeError::Message(msg) => write!(f, "{}", msg),
+            SerdeError::Decode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+impl From<BdecodingError> for SerdeError {
+    fn from(e: BdecodingError) -> Self {
+        SerdeError::Decode(e)
+    }
+}
+
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerdeError> {
+    Ok(bencode_element(&to_value(value)?))
+}
+
+// Serializes into `w`. The value is still built in memory first, but the encoding itself is streamed.
+pub fn to_writer<W: Write, T: Serialize + ?Sized>(w: &mut W, value: &T) -> Result<(), SerdeError> {
+    bencode_to_writer(w, &to_value(value)?).map_err(|e| SerdeError::Message(e.to_string()))
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<BencodeValue, SerdeError> {
+    match value.serialize(ValueSerializer)? {
+        Some(v) => Ok(v),
+        None => Err(SerdeError::Message("Cannot bencode a value that is None or unit.".to_string())),
+    }
+}
+
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, SerdeError> {
+    T::deserialize(RefDeserializer(bdecode_ref(bytes)?))
+}
+
+pub fn from_value<'de, T: Deserialize<'de>>(value: &'de BencodeValue) -> Result<T, SerdeError> {
+    T::deserialize(RefDeserializer(value.as_bencode_ref()))
+}
+
+// ------------------ SERIALIZER ------------------
+
+// Serializes into a BencodeValue. Returns None for values bencode has no encoding for (None and unit),
+// which dictionaries skip and everything else rejects.
+struct ValueSerializer;
+
+fn unsupported(what: &str) -> SerdeError {
+    SerdeError::Message(format!("Bencode does not support {}.", what))
+}
+
+fn require(value: Option<BencodeValue>) -> Result<BencodeValue, SerdeError> {
+    value.ok_or_else(|| unsupported("None or unit inside a list"))
+}
+
+fn variant_dict(variant: &'static str, value: BencodeValue) -> BencodeValue {
+    BencodeValue::Dictionary(BTreeMap::from([(variant.as_bytes().to_vec(), value)]))
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = SeqSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = MapSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, SerdeError> {
+        Ok(Some(BencodeValue::Integer(v.into())))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, SerdeError> {
+        Ok(Some(BencodeValue::Integer(v.into())))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, SerdeError> {
+        Ok(Some(BencodeValue::Integer(v.into())))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, SerdeError> {
+        Ok(Some(BencodeValue::Integer(v.into())))
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, SerdeError> {
+        Err(unsupported("floating point numbers"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, SerdeError> {
+        Err(unsupported("floating point numbers"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, SerdeError> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, SerdeError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, SerdeError> {
+        Ok(Some(BencodeValue::ByteString(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, SerdeError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, SerdeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, SerdeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Self::Ok, SerdeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<Self::Ok, SerdeError> {
+        let value = require(value.serialize(ValueSerializer)?)?;
+        Ok(Some(variant_dict(variant, value)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, SerdeError> {
+        Ok(SeqSerializer { variant: None, items: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize) -> Result<SeqSerializer, SerdeError> {
+        Ok(SeqSerializer { variant: Some(variant), items: Vec::with_capacity(len) })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer, SerdeError> {
+        Ok(MapSerializer { variant: None, map: BTreeMap::new(), next_key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<MapSerializer, SerdeError> {
+        Ok(MapSerializer { variant: Some(variant), map: BTreeMap::new(), next_key: None })
+    }
+}
+
+struct SeqSerializer {
+    variant: Option<&'static str>,
+    items: Vec<BencodeValue>,
+}
+
+impl SeqSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.items.push(require(value.serialize(ValueSerializer)?)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<BencodeValue>, SerdeError> {
+        let list = BencodeValue::List(self.items);
+        match self.variant {
+            Some(variant) => Ok(Some(variant_dict(variant, list))),
+            None => Ok(Some(list)),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+struct MapSerializer {
+    variant: Option<&'static str>,
+    map: BTreeMap<Vec<u8>, BencodeValue>,
+    next_key: Option<Vec<u8>>,
+}
+
+impl MapSerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), SerdeError> {
+        // None values are left out of the dictionary entirely.
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<BencodeValue>, SerdeError> {
+        let dict = BencodeValue::Dictionary(self.map);
+        match self.variant {
+            Some(variant) => Ok(Some(variant_dict(variant, dict))),
+            None => Ok(Some(dict)),
+        }
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        match key.serialize(ValueSerializer)? {
+            Some(BencodeValue::ByteString(k)) => {
+                self.next_key = Some(k);
+                Ok(())
+            }
+            _ => Err(unsupported("dictionary keys that are not strings or bytes")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self.next_key.take().ok_or_else(|| SerdeError::Message("serialize_value called before serialize_key.".to_string()))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+// ------------------ DESERIALIZER ------------------
+
+// Deserializes from a decoded BencodeRef. Byte strings are handed to the visitor borrowed, so `&'de [u8]`
+// and `&'de str` fields point straight into the input.
+struct RefDeserializer<'de>(BencodeRef<'de>);
+
+impl<'de> IntoDeserializer<'de, SerdeError> for RefDeserializer<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for RefDeserializer<'de> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            // Hand the visitor the narrowest type that holds the value. serde range-checks it against the field type.
+            BencodeRef::Integer(i) => {
+                if let Ok(v) = i.to_i64() {
+                    visitor.visit_i64(v)
+                } else if let Ok(v) = i.to_u64() {
+                    visitor.visit_u64(v)
+                } else if let Ok(v) = i.to_i128() {
+                    visitor.visit_i128(v)
+                } else if let Ok(v) = i.to_u128() {
+                    visitor.visit_u128(v)
+                } else {
+                    Err(SerdeError::Message(format!("Integer {} is too large to deserialize.", i)))
+                }
+            }
+            BencodeRef::ByteString(s) => match std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(s),
+            },
+            BencodeRef::List(l) => visitor.visit_seq(de::value::SeqDeserializer::new(l.into_iter().map(RefDeserializer))),
+            BencodeRef::Dictionary(d) => visitor.visit_map(de::value::MapDeserializer::new(
+                d.into_iter().map(|(k, v)| (RefDeserializer(BencodeRef::ByteString(k)), RefDeserializer(v)))
+            )),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0.as_integer().map(|i| i.to_i64()) {
+            Some(Ok(0)) => visitor.visit_bool(false),
+            Some(Ok(1)) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            BencodeRef::ByteString(s) => visitor.visit_borrowed_bytes(s),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    // A value that is present is always Some. Missing struct fields become None through serde's own handling.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            BencodeRef::ByteString(_) => visitor.visit_enum(EnumDeserializer { variant: self, value: None }),
+            BencodeRef::Dictionary(d) if d.len() == 1 => {
+                let (k, v) = d.into_iter().next().unwrap();
+                visitor.visit_enum(EnumDeserializer { variant: RefDeserializer(BencodeRef::ByteString(k)), value: Some(RefDeserializer(v)) })
+            }
+            _ => Err(SerdeError::Message("Expected an enum as a byte string or a dictionary with a single key.".to_string())),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct EnumDeserializer<'de> {
+    variant: RefDeserializer<'de>,
+    value: Option<RefDeserializer<'de>>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer<'de> {
+    type Error = SerdeError;
+    type Variant = VariantDeserializer<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantDeserializer<'de>), SerdeError> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, VariantDeserializer(self.value)))
+    }
+}
+
+struct VariantDeserializer<'de>(Option<RefDeserializer<'de>>);
+
+impl<'de> de::VariantAccess<'de> for VariantDeserializer<'de> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        match self.0 {
+            None => Ok(()),
+            Some(_) => Err(SerdeError::Message("Expected a unit variant.".to_string())),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+        match self.0 {
+            Some(value) => seed.deserialize(value),
+            None => Err(SerdeError::Message("Expected a newtype variant.".to_string())),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_seq(value, visitor),
+            None => Err(SerdeError::Message("Expected a tuple variant.".to_string())),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_map(value, visitor),
+            None => Err(SerdeError::Message("Expected a struct variant.".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        length: Option<u64>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Torrent {
+        announce: String,
+        info: Info,
+        comment: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Event {
+        Started,
+        Stopped,
+    }
+
+    #[test]
+    fn serialize_sorts_keys_and_skips_none() {
+        let torrent = Torrent {
+            announce: "http://tracker".to_string(),
+            info: Info { name: "a".to_string(), piece_length: 16, pieces: vec![0xFF, 0x00], length: None },
+            comment: None,
+        };
+        assert_eq!(
+            to_bytes(&torrent).unwrap(),
+            b"d8:announce14:http://tracker4:infod4:name1:a12:piece lengthi16e6:pieces2:\xFF\x00ee".to_vec()
+        );
+    }
+
+    #[test]
+    fn round_trip_struct() {
+        let torrent = Torrent {
+            announce: "http://tracker".to_string(),
+            info: Info { name: "a".to_string(), piece_length: 16, pieces: vec![0xFF; 40], length: Some(1 << 40) },
+            comment: Some("hello".to_string()),
+        };
+        let encoded = to_bytes(&torrent).unwrap();
+        assert_eq!(from_bytes::<Torrent>(&encoded).unwrap(), torrent);
+    }
+
+    #[test]
+    fn deserialize_borrowed_bytes() {
+        #[derive(Deserialize)]
+        struct Peer<'a> {
+            #[serde(rename = "peer id", with = "serde_bytes")]
+            peer_id: &'a [u8],
+            ip: &'a str,
+            port: u16,
+        }
+
+        let encoded = b"d2:ip9:127.0.0.17:peer id3:\x01\x02\x034:porti6881ee";
+        let peer: Peer = from_bytes(encoded).unwrap();
+        assert_eq!(peer.peer_id, b"\x01\x02\x03");
+        assert_eq!(peer.ip, "127.0.0.1");
+        assert_eq!(peer.port, 6881);
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        // Port does not fit in u16.
+        #[derive(Debug, Deserialize)]
+        struct Peer {
+            port: u16,
+        }
+        assert!(from_bytes::<Peer>(b"d4:porti70000ee").is_err());
+        assert!(from_bytes::<Peer>(b"de").is_err());
+        assert!(matches!(from_bytes::<Peer>(b"d4:port"), Err(SerdeError::Decode(_))));
+    }
+
+    #[test]
+    fn enums_and_bools() {
+        assert_eq!(to_bytes(&Event::Started).unwrap(), b"7:started".to_vec());
+        assert_eq!(from_bytes::<Event>(b"7:stopped").unwrap(), Event::Stopped);
+        assert_eq!(to_bytes(&(true, false)).unwrap(), b"li1ei0ee".to_vec());
+        assert_eq!(from_bytes::<Vec<bool>>(b"li1ei0ee").unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn wide_integers() {
+        assert_eq!(to_bytes(&u64::MAX).unwrap(), b"i18446744073709551615e".to_vec());
+        assert_eq!(from_bytes::<u64>(b"i18446744073709551615e").unwrap(), u64::MAX);
+        assert_eq!(from_bytes::<i128>(b"i-18446744073709551616e").unwrap(), -18446744073709551616);
+        assert!(from_bytes::<u64>(b"i18446744073709551616e").is_err());
+    }
+
+    #[test]
+    fn from_value_matches_from_bytes() {
+        let value = crate::bdecode::bdecode_element(b"d4:porti1ee").unwrap();
+        assert_eq!(from_value::<BTreeMap<String, i64>>(&value).unwrap(), BTreeMap::from([("port".to_string(), 1)]));
+    }
+}
@@ -0,0 +1,197 @@
+#![allow(dead_code)]
+
+// The first message on every peer connection, 68 bytes in both directions:
+//   19, "BitTorrent protocol", 8 reserved bytes, 20 byte info hash, 20 byte peer id
+// Reserved bits advertise protocol extensions. A peer for another torrent or speaking another protocol is dropped.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 68;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+/// Extensions a peer advertises in the reserved bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// BEP 10 extension protocol: bit 0x10 of byte 5.
+    pub extension_protocol: bool,
+    /// BEP 6 fast extension: bit 0x04 of byte 7.
+    pub fast: bool,
+    /// BEP 5 DHT: bit 0x01 of byte 7.
+    pub dht: bool,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    // The peer's protocol string, length byte included.
+    InvalidProtocol(Vec<u8>),
+    InfoHashMismatch { expected: [u8; 20], found: [u8; 20] },
+    PeerIdMismatch { expected: Vec<u8>, found: [u8; 20] },
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "handshake failed: {}", e),
+            HandshakeError::InvalidProtocol(p) => write!(f, "peer speaks {:?}, not the BitTorrent protocol", String::from_utf8_lossy(p)),
+            HandshakeError::InfoHashMismatch { .. } => write!(f, "peer is serving a different torrent"),
+            HandshakeError::PeerIdMismatch { expected, found } => write!(
+                f,
+                "peer id is {:?}, the tracker said {:?}",
+                String::from_utf8_lossy(found),
+                String::from_utf8_lossy(expected)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+impl Handshake {
+    /// Our handshake. We don't implement any extensions, so every reserved bit is 0.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+        Handshake { reserved: [0; 8], info_hash, peer_id }
+    }
+
+    pub fn to_bytes(self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> Result<Handshake, HandshakeError> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol(bytes[..20].to_vec()));
+        }
+        Ok(Handshake {
+            reserved: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        })
+    }
+
+    pub fn extensions(&self) -> Extensions {
+        Extensions {
+            extension_protocol: self.reserved[5] & 0x10 != 0,
+            fast: self.reserved[7] & 0x04 != 0,
+            dht: self.reserved[7] & 0x01 != 0,
+        }
+    }
+}
+
+/// Sends our handshake and reads and checks the peer's. `expected_peer_id` is the id the tracker gave for this
+/// peer, if any; compact peer lists don't include one.
+pub fn perform_handshake<S: Read + Write>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    expected_peer_id: Option<&[u8]>,
+) -> Result<Handshake, HandshakeError> {
+    stream.write_all(&Handshake::new(*info_hash, *peer_id).to_bytes())?;
+    stream.flush()?;
+
+    let mut reply = [0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut reply)?;
+    let handshake = Handshake::from_bytes(&reply)?;
+
+    if handshake.info_hash != *info_hash {
+        return Err(HandshakeError::InfoHashMismatch { expected: *info_hash, found: handshake.info_hash });
+    }
+    if let Some(expected) = expected_peer_id
+        && expected != handshake.peer_id
+    {
+        return Err(HandshakeError::PeerIdMismatch { expected: expected.to_vec(), found: handshake.peer_id });
+    }
+    Ok(handshake)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Reads come from `input`, writes go to `output`.
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn pipe(reply: &[u8]) -> Pipe {
+        Pipe { input: Cursor::new(reply.to_vec()), output: Vec::new() }
+    }
+
+    #[test]
+    fn exchanges_handshakes() {
+        let mut theirs = Handshake::new([1; 20], *b"-XX0001-theirpeerid!");
+        theirs.reserved[5] = 0x10;
+        theirs.reserved[7] = 0x05;
+        // Whatever follows the handshake is left in the stream.
+        let mut reply = theirs.to_bytes().to_vec();
+        reply.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut stream = pipe(&reply);
+        let handshake = perform_handshake(&mut stream, &[1; 20], b"-CR0100-ourpeerid!!!", Some(b"-XX0001-theirpeerid!")).unwrap();
+        assert_eq!(handshake, theirs);
+        assert_eq!(handshake.extensions(), Extensions { extension_protocol: true, fast: true, dht: true });
+        assert_eq!(stream.input.position(), 68);
+
+        // We sent our own id, not theirs.
+        assert_eq!(stream.output.len(), 68);
+        assert_eq!(&stream.output[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&stream.output[48..], b"-CR0100-ourpeerid!!!");
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let ours = *b"-CR0100-ourpeerid!!!";
+        let theirs = Handshake::new([1; 20], [2; 20]).to_bytes();
+
+        let result = perform_handshake(&mut pipe(&theirs), &[9; 20], &ours, None);
+        assert!(matches!(result, Err(HandshakeError::InfoHashMismatch { expected, found }) if expected == [9; 20] && found == [1; 20]));
+
+        let result = perform_handshake(&mut pipe(&theirs), &[1; 20], &ours, Some(&[3; 20]));
+        assert!(matches!(result, Err(HandshakeError::PeerIdMismatch { found, .. }) if found == [2; 20]));
+
+        let mut wrong_protocol = theirs;
+        wrong_protocol[1] = b'b';
+        let result = perform_handshake(&mut pipe(&wrong_protocol), &[1; 20], &ours, None);
+        assert!(matches!(result, Err(HandshakeError::InvalidProtocol(_))));
+
+        let result = perform_handshake(&mut pipe(&theirs[..40]), &[1; 20], &ours, None);
+        assert!(matches!(result, Err(HandshakeError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -1,127 +1,160 @@
 #![allow(unused)]
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
 
 use std::env;
 use std::fs;
 
+mod announce_scheduler;
 mod bencode;
+mod bencode_int;
+mod bencode_json;
+mod bencode_query;
 mod bdecode;
+mod handshake;
+mod bencode_serde;
+mod metainfo;
+mod peer_connection;
+mod peer_id;
+mod peer_message;
+mod peers;
+mod storage;
 mod tracker_request;
+mod udp_tracker;
 
 use bencode::{BencodeValue, bencode_element};
-use bdecode::bdecode_element;
+use bdecode::{bdecode_detailed, DecodeOptions};
+use announce_scheduler::{AnnounceScheduler, ScheduledAnnounce};
+use peer_connection::PeerConnection;
+use peer_message::PeerMessage;
+use metainfo::Metainfo;
+use peers::PeerInfo;
+use tracker_request::{AnnounceRequest, AnnounceResponse, TrackerTiers};
 
 use std::net::TcpStream;
 use std::io::prelude::*;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io::{self, Write};
 
 fn is_tcp_port_open(address: &str, port: u16, timeout: Duration) -> bool {
     let full_address = format!("{}:{}", address, port);
-    match TcpStream::connect_timeout(&full_address.parse().unwrap(), timeout) {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    TcpStream::connect_timeout(&full_address.parse().unwrap(), timeout).is_ok()
 }
 
-#[derive(Debug, Default, Clone)]
-struct PeerInfo{
-    ip: String,
-    peer_id: Vec<u8>, // 20 byte peer id.
-    port: u16 // to support ipv6 in the future.
-}
+// Connects, says we are interested and prints what the peer tells us until it unchokes us or goes quiet.
+fn talk_to_peer(active_peer: &PeerInfo, metainfo: &Metainfo, peer_id: &[u8; 20]) -> Result<PeerConnection, Box<dyn std::error::Error>> {
+    let num_pieces = metainfo.info.num_pieces() as u32;
+    let expected_peer_id = active_peer.peer_id.as_deref();
+    let mut conn = PeerConnection::connect(active_peer.addr, &metainfo.info_hash, peer_id, expected_peer_id, num_pieces, Duration::new(60, 0))?;
+    println!(
+        "Handshake with {} (peer id {:?}): {:?}",
+        conn.addr(),
+        String::from_utf8_lossy(&conn.handshake().peer_id),
+        conn.handshake().extensions()
+    );
 
-// impl fmt::Display for PeerInfo {
-    // fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // write!(f, "{:?}", self)
-    // }
-// }
-
-// impl std::error::Error for PeerInfo {}
-
-
-fn get_info_from_peer_dict(peer: &BencodeValue) -> Option<PeerInfo> {
-    let dict = bencode::get_dictionary(peer).ok()?; // TODO: check if this is correct.
-
-    match dict.get(&b"ip"[..]) {
-        None => None,
-        Some(ip) => {
-            match dict.get(&b"peer id"[..]) {
-                None => None,
-                Some(peer_id) => {
-                    match dict.get(&b"port"[..]) {
-                        None => None,
-                        Some(port) => {
-                            Some(PeerInfo {
-                                ip: bencode::get_utf8_lossy(&ip).ok()?, 
-                                peer_id: bencode::get_bytestring(&peer_id).ok()?,
-                                port: bencode::get_integer(&port).ok()?.try_into().unwrap() // this absolute garbage. TODO: Change return type from Option to Result. Change field type of bencoding integer from i64 to i128.
-                            })
-                        }
-                    }
-                }
-            }
+    conn.send(&PeerMessage::Interested)?;
+    let deadline = Instant::now() + Duration::new(30, 0);
+    while !conn.can_request() && Instant::now() < deadline {
+        for event in conn.poll(Duration::new(1, 0))? {
+            println!("{}: {event:?}", conn.addr());
         }
     }
+    Ok(conn)
 }
 
-fn get_all_peers_info(tracker_response: &BTreeMap<Vec<u8>, BencodeValue>) -> Result<Vec<PeerInfo>, Box<dyn std::error::Error>> { 
-    match tracker_response.get(&b"peers"[..]){
-        Some(val) => {
-            let BencodeValue::List(peers) = val else {
-                eprintln!("ERROR: 'peers' field is not a List of Dictionairies"); 
-                return Err(format!("tracker_response = {tracker_response:#?}").into());
-            };
-
-            let mut vec = Vec::<PeerInfo>::new();
-            for peer in peers {
-                match get_info_from_peer_dict(peer) {
-                    None => {continue;},
-                    Some(peer_info) => {vec.push(peer_info);}
-                }
+// Give up on the trackers after this many announces in a row failed (15s, 30s, 60s and 120s apart).
+const MAX_FAILED_ANNOUNCES: u32 = 5;
+
+// Sends the announce the scheduler says is due and tells the scheduler how it went.
+fn send_announce(
+    scheduler: &mut AnnounceScheduler,
+    trackers: &mut TrackerTiers,
+    request: &mut AnnounceRequest,
+    due: ScheduledAnnounce,
+) -> Option<AnnounceResponse> {
+    request.event = due.event();
+    request.trackerid = scheduler.tracker_id().map(str::to_string);
+    match tracker_request::get_tracker_response(trackers, request) {
+        Ok((response, warnings)) => {
+            for warning in &warnings {
+                eprintln!("WARNING: Tracker response: {warning}");
+            }
+            scheduler.on_success(Instant::now(), &response);
+            if let Some(warning) = &response.warning_message {
+                eprintln!("WARNING: Tracker says: {warning}");
             }
-            Ok(vec)
-        },
-        None => {
-            eprintln!("ERROR: Tracker response does not have a field named 'peers'.");
-            Err(format!("tracker_response = {tracker_response:#?}").into())
+            Some(response)
+        }
+        Err(e) => {
+            scheduler.on_failure(Instant::now());
+            eprintln!("WARNING: Announce failed: {e}");
+            None
         }
     }
 }
 
-fn perform_handshake(active_peer: &PeerInfo, torrent: &BTreeMap<Vec<u8>, BencodeValue>) -> Result<(), Box<dyn std::error::Error>> {
-    let peer_url = active_peer.ip.clone() + ":" + &active_peer.port.to_string(); 
-
-    let mut stream = TcpStream::connect_timeout(&peer_url.parse().unwrap(), Duration::new(60, 0))?;
-    
-    let pstr = b"BitTorrent protocol";
-    let pstrlen = [pstr.len() as u8]; // single byte 19
-    let reserved = [0u8; 8];
+fn print_usage_and_exit(program: &str) -> ! {
+    eprintln!("Usage: {} <path to .torrent file>", program);
+    eprintln!("       {} dump [--json] <path to bencoded file>", program);
+    eprintln!("       {} scrape <path to .torrent file>...", program);
+    eprintln!("Set CORRENT_PEER_ID_PREFIX to replace the default '-CR0100-' peer id prefix.");
+    std::process::exit(1);
+}
 
-    let mut handshake = Vec::new();
-    handshake.extend_from_slice(&pstrlen);
-    handshake.extend_from_slice(pstr);
-    handshake.extend_from_slice(&reserved);
-    handshake.extend_from_slice(&tracker_request::get_info_hash(&torrent));
-    handshake.extend_from_slice(&active_peer.peer_id);
+// Prints any bencoded file (a .torrent, a saved tracker response, ...) as JSON.
+// Binary byte strings are shown as hex instead of mojibake.
+fn dump(program: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (format, path) = match args {
+        [path] => ("--json", path),
+        [format, path] => (format.as_str(), path),
+        _ => print_usage_and_exit(program),
+    };
 
-    assert_eq!(handshake.len(), 68); // sanity check
+    let bytes = fs::read(path)?;
+    let decoded = bdecode_detailed(&bytes, &[], DecodeOptions::lenient())?;
+    for warning in &decoded.warnings {
+        eprintln!("WARNING: {path}: {warning}");
+    }
+    let value = decoded.value.to_value();
 
-    stream.write_all(&handshake)?;
-    stream.flush()?;
+    match format {
+        "--json" => println!("{}", bencode_json::to_json_string_pretty(&value)),
+        _ => print_usage_and_exit(program),
+    }
+    Ok(())
+}
 
-    let mut buf = vec![0u8; 70];
-    let num_bytes_read = stream.read(&mut buf)?;
+// Prints seeders/leechers for each torrent. Torrents with the same trackers are scraped in one batch, failing over
+// between trackers like announces do.
+fn scrape(program: &str, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if paths.is_empty() {
+        print_usage_and_exit(program);
+    }
 
-    if num_bytes_read == 0 {
-        return Err("No bytes read from connection".into());
+    let mut by_trackers = BTreeMap::<Vec<Vec<String>>, Vec<(&String, [u8; 20])>>::new();
+    for path in paths {
+        let metainfo = Metainfo::from_bytes(&fs::read(path)?)?;
+        by_trackers.entry(metainfo.announce_tiers()).or_default().push((path, metainfo.info_hash));
     }
 
-    println!("num_bytes_read = {num_bytes_read:#?}");
-    println!("Buffer input in UTF-8 = {}", String::from_utf8_lossy(&buf));
-    println!("Buffer input in raw = {:?}", buf);
+    for (tiers, torrents) in by_trackers {
+        let hashes = torrents.iter().map(|(_, hash)| *hash).collect::<Vec<_>>();
+        let mut trackers = TrackerTiers::new(tiers);
+        trackers.shuffle();
+        match trackers.try_each(|tracker| tracker_request::scrape(tracker, &hashes).map(|stats| (tracker.to_string(), stats))) {
+            Ok((tracker, stats)) => {
+                for (path, hash) in &torrents {
+                    match stats.get(hash) {
+                        Some(s) => println!("{path}: {} seeders, {} leechers, {} downloads", s.complete, s.incomplete, s.downloaded),
+                        None => println!("{path}: unknown to {tracker}"),
+                    }
+                }
+            }
+            Err(e) => eprintln!("ERROR: Could not scrape: {e}"),
+        }
+    }
     Ok(())
 }
 
@@ -130,42 +163,95 @@ fn main() ->  Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        eprintln!("Usage: {} <path to .torrent file>", args[0]);
-        std::process::exit(1);
+        print_usage_and_exit(&args[0]);
+    }
+
+    if args[1] == "dump" {
+        return dump(&args[0], &args[2..]);
+    }
+    if args[1] == "scrape" {
+        return scrape(&args[0], &args[2..]);
     }
 
     // Get file path of the .torrent file.
     let path = &args[1];
 
     // Read file
-    let bytes = fs::read(path).expect("Failed to read file");
+    let bytes = fs::read(path)?;
 
     // Extract the structured metadata from the torrent file.
-    let BencodeValue::Dictionary(torrent) = bdecode_element(&bytes)? else {
-        eprintln!("ERROR: Torrent file is not a dictionary.");
-        std::process::exit(1);
-    };
+    let (metainfo, warnings) = Metainfo::from_bytes_with_warnings(&bytes)?;
+    for warning in &warnings {
+        eprintln!("WARNING: {path}: {warning}");
+    }
 
     // Get a valid response from the tracker. Contains a list of IP addresses of the peers.
-    let BencodeValue::Dictionary(tracker_response) = tracker_request::get_tracker_response(&torrent)? else {
-        eprintln!("ERROR: Tracker response is not a dictionary.");
-        std::process::exit(1);
+    let mut trackers = TrackerTiers::new(metainfo.announce_tiers());
+    trackers.shuffle();
+    // One peer id for the whole session, in announces and handshakes alike.
+    let peer_id = match env::var("CORRENT_PEER_ID_PREFIX") {
+        Ok(prefix) => peer_id::generate_peer_id_with_prefix(prefix.as_bytes())?,
+        Err(_) => peer_id::generate_peer_id(),
     };
+    let mut announce = AnnounceRequest::new(&metainfo, peer_id, 6881);
+    announce.ipv6 = tracker_request::local_ipv6_addr();
 
-    let all_peers_info = get_all_peers_info(&tracker_response)?;
-    if all_peers_info.len() == 0 {
-        eprintln!("ERROR: Could not find any valid peer given by the tracker.");
-        std::process::exit(1);
-    };
+    // Nothing is transferred yet, so every announce reports these.
+    let (uploaded, downloaded) = (0, 0);
 
-    let mut active_peer = PeerInfo::default();
-    let mut active_cnt = 0;
-    for peer_info in &all_peers_info {
-        match perform_handshake(&peer_info, &torrent) {
-            Ok(()) => { break; },
-            Err(_) => { continue; }
+    // Try peers one by one until one of them talks to us. The scheduler decides when to announce: `started`
+    // first, retried with backoff if it fails, then a regular announce every interval, which refills the peers.
+    let mut scheduler = AnnounceScheduler::new(Instant::now());
+    let mut peers = VecDeque::<PeerInfo>::new();
+    let mut failed_announces = 0;
+    let mut connected = false;
+    loop {
+        if let Some(due) = scheduler.poll(Instant::now()) {
+            announce.uploaded = uploaded;
+            announce.downloaded = downloaded;
+            announce.left = metainfo.info.length - downloaded;
+            match send_announce(&mut scheduler, &mut trackers, &mut announce, due) {
+                Some(response) => {
+                    failed_announces = 0;
+                    peers = response.peers.into();
+                }
+                None => failed_announces += 1,
+            }
         }
+
+        if let Some(peer_info) = peers.pop_front() {
+            match talk_to_peer(&peer_info, &metainfo, &peer_id) {
+                Ok(_) => {
+                    connected = true;
+                    break;
+                }
+                Err(e) => eprintln!("WARNING: Peer {}: {e}", peer_info.addr),
+            }
+            continue;
+        }
+
+        if failed_announces == 0 {
+            eprintln!("ERROR: Could not find any valid peer given by the tracker.");
+            break;
+        }
+        if failed_announces >= MAX_FAILED_ANNOUNCES {
+            eprintln!("ERROR: Giving up after {failed_announces} failed announces.");
+            break;
+        }
+        if let Some(next_at) = scheduler.next_at() {
+            std::thread::sleep(next_at.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    // Let the tracker know we are leaving so it stops handing out our address. Nothing to do if it never heard
+    // of us.
+    scheduler.on_shutdown(Instant::now());
+    if let Some(stop) = scheduler.poll(Instant::now()) {
+        send_announce(&mut scheduler, &mut trackers, &mut announce, stop);
+    }
+
+    if !connected {
+        std::process::exit(1);
     }
-    
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,393 @@
+#![allow(dead_code)]
+
+// Typed view of a .torrent file. Everything is validated up front so the rest of the client never has to
+// poke around in a BTreeMap<Vec<u8>, BencodeValue> or deal with a missing field halfway through a download.
+
+use std::fmt;
+
+use sha1::{Digest, Sha1};
+
+use crate::bdecode::{BdecodingError, DecodeOptions, DecodeWarning, PathSegment, bdecode_detailed};
+use crate::bencode::BencodeRef;
+use crate::bencode_query::{BencodeQuery, QueryError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metainfo {
+    /// None if the torrent only has 'announce-list'.
+    pub announce: Option<String>,
+    /// BEP 12 tiers from 'announce-list', in file order. Empty if the torrent only has 'announce'.
+    pub announce_list: Vec<Vec<String>>,
+    pub info: Info,
+    /// SHA-1 of the raw bytes of the info dictionary.
+    pub info_hash: [u8; 20],
+    /// Seconds since the UNIX epoch.
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    /// File name for single-file torrents, root directory name for multi-file torrents.
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    /// Total size of the torrent's content in bytes. The sum of every file's length.
+    pub length: u64,
+    /// Files in the order they appear in the piece/byte space. A single-file torrent has exactly one entry.
+    pub files: Vec<FileEntry>,
+    pub is_multi_file: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub length: u64,
+    /// Path components relative to `Info::name`. Empty for a single-file torrent, where `name` is the file itself.
+    pub path: Vec<String>,
+    pub md5sum: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetainfoError {
+    Decode(BdecodingError),
+    // A field is missing or has the wrong type.
+    Field(QueryError),
+    // A field is present but its value makes no sense.
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetainfoError::Decode(e) => write!(f, "torrent is not valid bencode: {}", e),
+            MetainfoError::Field(e) => write!(f, "torrent field error: {}", e),
+            MetainfoError::Invalid { field, reason } => write!(f, "invalid torrent field '{}': {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for MetainfoError {}
+
+impl From<BdecodingError> for MetainfoError {
+    fn from(e: BdecodingError) -> Self {
+        MetainfoError::Decode(e)
+    }
+}
+
+impl From<QueryError> for MetainfoError {
+    fn from(e: QueryError) -> Self {
+        MetainfoError::Field(e)
+    }
+}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> MetainfoError {
+    MetainfoError::Invalid { field, reason: reason.into() }
+}
+
+// Names end up as paths on disk, so anything that could escape the download directory is rejected.
+fn validate_path_component(field: &'static str, component: &str) -> Result<(), MetainfoError> {
+    if component.is_empty() || component == "." || component == ".." || component.contains(['/', '\\', '\0']) {
+        return Err(invalid(field, format!("{:?} is not a safe path component", component)));
+    }
+    Ok(())
+}
+
+// Empty tiers carry no trackers and are dropped.
+fn announce_list(torrent: &BencodeRef) -> Result<Vec<Vec<String>>, MetainfoError> {
+    let Some(list) = torrent.get_opt_list_at("announce-list")? else {
+        return Ok(Vec::new());
+    };
+
+    let mut tiers = Vec::new();
+    for (i, tier) in list.iter().enumerate() {
+        let Some(urls) = tier.as_list() else {
+            return Err(invalid("announce-list", format!("tier {} is {}, expected a list", i, tier.type_name())));
+        };
+        let tier = urls
+            .iter()
+            .map(|url| match url.as_bytestring().map(std::str::from_utf8) {
+                Some(Ok(url)) => Ok(url.to_string()),
+                _ => Err(invalid("announce-list", format!("tier {} has a tracker that is not a UTF-8 string", i))),
+            })
+            .collect::<Result<Vec<String>, MetainfoError>>()?;
+        if !tier.is_empty() {
+            tiers.push(tier);
+        }
+    }
+    Ok(tiers)
+}
+
+impl Metainfo {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Metainfo, MetainfoError> {
+        Ok(Metainfo::from_bytes_with_warnings(bytes)?.0)
+    }
+
+    /// Plenty of torrents in the wild are not canonical bencode, so they are decoded leniently.
+    /// The returned warnings say what was tolerated.
+    pub fn from_bytes_with_warnings(bytes: &[u8]) -> Result<(Metainfo, Vec<DecodeWarning>), MetainfoError> {
+        let decoded = bdecode_detailed(bytes, &[PathSegment::Key(b"info")], DecodeOptions::lenient())?;
+        // Fields are read from the borrowed tree, so 'pieces' is only copied once, into Info::pieces.
+        let torrent = &decoded.value;
+
+        let Some(raw_info) = decoded.raw else {
+            return Err(QueryError::MissingKey { path: "info".to_string(), segment: 0 }.into());
+        };
+
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&Sha1::digest(raw_info));
+
+        // Either is enough to find a tracker. Clients that know BEP 12 ignore 'announce' when 'announce-list' is
+        // there, so some torrents leave it out.
+        let announce = torrent.get_opt_str_at("announce")?.map(str::to_string);
+        let announce_list = announce_list(torrent)?;
+        if announce.is_none() && announce_list.is_empty() {
+            return Err(QueryError::MissingKey { path: "announce".to_string(), segment: 0 }.into());
+        }
+
+        let metainfo = Metainfo {
+            announce,
+            announce_list,
+            info: Info::from_value(torrent.query("info")?)?,
+            info_hash,
+            creation_date: torrent.get_opt_i64_at("creation date")?,
+            comment: torrent.get_opt_text_at("comment")?,
+            created_by: torrent.get_opt_text_at("created by")?,
+            encoding: torrent.get_opt_text_at("encoding")?,
+        };
+        Ok((metainfo, decoded.warnings))
+    }
+
+    /// Tracker tiers to announce to. 'announce-list' takes precedence over 'announce' when present.
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.is_empty() {
+            self.announce.iter().map(|a| vec![a.clone()]).collect()
+        } else {
+            self.announce_list.clone()
+        }
+    }
+}
+
+impl Info {
+    fn from_value(info: &BencodeRef) -> Result<Info, MetainfoError> {
+        let name = String::from_utf8_lossy(info.get_bytes_at("name")?).into_owned();
+        validate_path_component("info.name", &name)?;
+
+        let piece_length = info.get_u64_at("piece length")?;
+        if piece_length == 0 {
+            return Err(invalid("info.piece length", "piece length is 0"));
+        }
+
+        let raw_pieces = info.get_bytes_at("pieces")?;
+        if raw_pieces.len() % 20 != 0 {
+            return Err(invalid("info.pieces", format!("length {} is not a multiple of 20", raw_pieces.len())));
+        }
+        let pieces = raw_pieces
+            .chunks_exact(20)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect::<Vec<[u8; 20]>>();
+
+        // Multi-file torrents have 'files' instead of 'length'.
+        let is_multi_file = info.query_opt("files")?.is_some();
+        let files = if is_multi_file {
+            Info::files_from_value(info)?
+        } else {
+            vec![FileEntry { length: info.get_u64_at("length")?, path: Vec::new(), md5sum: info.get_opt_text_at("md5sum")? }]
+        };
+
+        let length = files
+            .iter()
+            .try_fold(0u64, |total, f| total.checked_add(f.length))
+            .ok_or_else(|| invalid("info.files", "total length overflows u64"))?;
+        let expected_pieces = length.div_ceil(piece_length);
+        if pieces.len() as u64 != expected_pieces {
+            return Err(invalid("info.pieces", format!(
+                "{} piece hashes given but a length of {} with piece length {} needs {}",
+                pieces.len(), length, piece_length, expected_pieces
+            )));
+        }
+
+        Ok(Info { name, piece_length, pieces, length, files, is_multi_file })
+    }
+
+    fn files_from_value(info: &BencodeRef) -> Result<Vec<FileEntry>, MetainfoError> {
+        let entries = info.get_list_at("files")?;
+        if entries.is_empty() {
+            return Err(invalid("info.files", "file list is empty"));
+        }
+
+        let mut files = Vec::with_capacity(entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            let components = entry.get_list_at("path")?;
+            if components.is_empty() {
+                return Err(invalid("info.files.path", format!("file {} has an empty path", i)));
+            }
+
+            let mut path = Vec::with_capacity(components.len());
+            for component in components {
+                let Some(bytes) = component.as_bytestring() else {
+                    return Err(invalid("info.files.path", format!("file {} has a path component that is {}", i, component.type_name())));
+                };
+                let component = String::from_utf8_lossy(bytes).into_owned();
+                validate_path_component("info.files.path", &component)?;
+                path.push(component);
+            }
+
+            files.push(FileEntry { length: entry.get_u64_at("length")?, path, md5sum: entry.get_opt_text_at("md5sum")? });
+        }
+        Ok(files)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Size of piece `index` in bytes. Every piece is `piece_length` long except possibly the last.
+    pub fn piece_size(&self, index: usize) -> u64 {
+        if index + 1 == self.pieces.len() {
+            self.length - self.piece_length * index as u64
+        } else {
+            self.piece_length
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_file_torrent(info: &[u8]) -> Vec<u8> {
+        let mut t = b"d8:announce18:http://tracker/ann7:comment5:hello13:creation datei1392719706e4:info".to_vec();
+        t.extend_from_slice(info);
+        t.push(b'e');
+        t
+    }
+
+    #[test]
+    fn parses_single_file_torrent() {
+        let mut info = b"d6:lengthi5e4:name5:a.txt12:piece lengthi4e6:pieces40:".to_vec();
+        info.extend_from_slice(&[1u8; 20]);
+        info.extend_from_slice(&[2u8; 20]);
+        info.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&single_file_torrent(&info)).unwrap();
+        assert_eq!(metainfo.announce.as_deref(), Some("http://tracker/ann"));
+        assert_eq!(metainfo.comment.as_deref(), Some("hello"));
+        assert_eq!(metainfo.creation_date, Some(1392719706));
+        assert_eq!(metainfo.created_by, None);
+        assert_eq!(metainfo.info.name, "a.txt");
+        assert_eq!(metainfo.info.pieces, vec![[1u8; 20], [2u8; 20]]);
+        assert_eq!(metainfo.info.piece_size(0), 4);
+        assert_eq!(metainfo.info.piece_size(1), 1);
+        assert!(!metainfo.info.is_multi_file);
+        assert_eq!(metainfo.info.files, vec![FileEntry { length: 5, path: vec![], md5sum: None }]);
+        assert_eq!(metainfo.info_hash.to_vec(), Sha1::digest(&info).to_vec());
+    }
+
+    #[test]
+    fn parses_multi_file_torrent() {
+        let mut info = b"d5:filesld6:lengthi3e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl3:sub5:a.txteed6:lengthi6e4:pathl5:b.bineee4:name3:dir12:piece lengthi4e6:pieces60:".to_vec();
+        info.extend_from_slice(&[0u8; 60]);
+        info.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&single_file_torrent(&info)).unwrap();
+        assert!(metainfo.info.is_multi_file);
+        assert_eq!(metainfo.info.length, 9);
+        assert_eq!(metainfo.info.files, vec![
+            FileEntry { length: 3, path: vec!["sub".to_string(), "a.txt".to_string()], md5sum: Some("0123456789abcdef0123456789abcdef".to_string()) },
+            FileEntry { length: 6, path: vec!["b.bin".to_string()], md5sum: None },
+        ]);
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        let mut info = b"d5:filesld6:lengthi1e4:pathl2:..6:passwdeee4:name3:dir12:piece lengthi4e6:pieces20:".to_vec();
+        info.extend_from_slice(&[0u8; 20]);
+        info.push(b'e');
+        assert!(matches!(
+            Metainfo::from_bytes(&single_file_torrent(&info)),
+            Err(MetainfoError::Invalid { field: "info.files.path", .. })
+        ));
+
+        let mut info = b"d5:filesld6:lengthi1e4:pathli1eeee4:name3:dir12:piece lengthi4e6:pieces20:".to_vec();
+        info.extend_from_slice(&[0u8; 20]);
+        info.push(b'e');
+        assert_eq!(
+            Metainfo::from_bytes(&single_file_torrent(&info)).unwrap_err().to_string(),
+            "invalid torrent field 'info.files.path': file 0 has a path component that is an integer"
+        );
+
+        let info = b"d6:lengthi0e4:name4:a/..12:piece lengthi4e6:pieces0:e";
+        assert!(matches!(
+            Metainfo::from_bytes(&single_file_torrent(info)),
+            Err(MetainfoError::Invalid { field: "info.name", .. })
+        ));
+    }
+
+    #[test]
+    fn parses_announce_list() {
+        let info = b"d6:lengthi0e4:name1:a12:piece lengthi4e6:pieces0:e";
+        let mut t = b"d8:announce1:a13:announce-listll1:a1:belel1:cee4:info".to_vec();
+        t.extend_from_slice(info);
+        t.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&t).unwrap();
+        assert_eq!(metainfo.announce_list, vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]]);
+        assert_eq!(metainfo.announce_tiers(), metainfo.announce_list);
+
+        let metainfo = Metainfo::from_bytes(&single_file_torrent(info)).unwrap();
+        assert!(metainfo.announce_list.is_empty());
+        assert_eq!(metainfo.announce_tiers(), vec![vec!["http://tracker/ann".to_string()]]);
+
+        // 'announce' can be left out when 'announce-list' has a tracker, but not when it is empty too.
+        let mut t = b"d13:announce-listll1:aee4:info".to_vec();
+        t.extend_from_slice(info);
+        t.push(b'e');
+        let metainfo = Metainfo::from_bytes(&t).unwrap();
+        assert_eq!(metainfo.announce, None);
+        assert_eq!(metainfo.announce_tiers(), vec![vec!["a".to_string()]]);
+
+        let mut t = b"d13:announce-listle4:info".to_vec();
+        t.extend_from_slice(info);
+        t.push(b'e');
+        assert_eq!(
+            Metainfo::from_bytes(&t),
+            Err(MetainfoError::Field(QueryError::MissingKey { path: "announce".to_string(), segment: 0 }))
+        );
+    }
+
+    #[test]
+    fn info_hash_uses_raw_bytes() {
+        // Unsorted keys: re-encoding the decoded dictionary would produce different bytes.
+        let mut info = b"d4:name1:a6:lengthi1e12:piece lengthi1e6:pieces20:".to_vec();
+        info.extend_from_slice(&[7u8; 20]);
+        info.push(b'e');
+
+        let (metainfo, warnings) = Metainfo::from_bytes_with_warnings(&single_file_torrent(&info)).unwrap();
+        assert_eq!(metainfo.info_hash.to_vec(), Sha1::digest(&info).to_vec());
+        assert!(!warnings.is_empty());
+    }
+
+    #[test]
+    fn reports_typed_errors() {
+        assert!(matches!(Metainfo::from_bytes(b"d8:announce1:ae"), Err(MetainfoError::Field(QueryError::MissingKey { .. }))));
+        assert!(matches!(Metainfo::from_bytes(b"d8:announce"), Err(MetainfoError::Decode(_))));
+
+        let bad_pieces = single_file_torrent(b"d6:lengthi5e4:name1:a12:piece lengthi4e6:pieces3:abce");
+        assert!(matches!(Metainfo::from_bytes(&bad_pieces), Err(MetainfoError::Invalid { field: "info.pieces", .. })));
+
+        let zero_piece_length = single_file_torrent(b"d6:lengthi5e4:name1:a12:piece lengthi0e6:pieces0:e");
+        assert!(matches!(Metainfo::from_bytes(&zero_piece_length), Err(MetainfoError::Invalid { field: "info.piece length", .. })));
+    }
+
+    #[test]
+    fn parses_sample_torrent() {
+        let bytes = std::fs::read("bitcoin.pdf-8c271f4d2e92a3449e2d1bde633cd49f64af888f.torrent").unwrap();
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.info.name, "bitcoin.pdf");
+        assert_eq!(
+            crate::bencode_json::to_hex(&metainfo.info_hash),
+            "8c271f4d2e92a3449e2d1bde633cd49f64af888f"
+        );
+    }
+}
@@ -0,0 +1,450 @@
+#![allow(dead_code)]
+
+// One connection to one peer, after the handshake. Tracks the four choke/interest flags from the README's
+// "Communicating with other peers" section and which pieces the peer has, and turns incoming messages into events
+// for the download logic.
+//
+// Both sides start out choking and not interested. Blocks only flow to us once we are interested and the peer has
+// unchoked us. A connection with no traffic for two minutes is considered dead, so we send keep-alives well before.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::handshake::{Handshake, HandshakeError, perform_handshake};
+use crate::peer_message::{MessageDecoder, MessageError, PeerMessage};
+
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub enum PeerError {
+    Io(io::Error),
+    Handshake(HandshakeError),
+    Message(MessageError),
+    // The peer closed the connection.
+    Closed,
+    // Nothing heard from the peer within the idle timeout.
+    IdleTimeout,
+    InvalidBitfield { len: usize, expected: usize },
+    // A bitfield may only be the first message after the handshake.
+    UnexpectedBitfield,
+    InvalidPieceIndex(u32),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Io(e) => write!(f, "connection error: {}", e),
+            PeerError::Handshake(e) => write!(f, "{}", e),
+            PeerError::Message(e) => write!(f, "invalid message: {}", e),
+            PeerError::Closed => write!(f, "peer closed the connection"),
+            PeerError::IdleTimeout => write!(f, "peer has been silent for too long"),
+            PeerError::InvalidBitfield { len, expected } => write!(f, "bitfield is {} bytes, expected {}", len, expected),
+            PeerError::UnexpectedBitfield => write!(f, "bitfield sent after other messages"),
+            PeerError::InvalidPieceIndex(index) => write!(f, "piece index {} is out of range", index),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        PeerError::Io(e)
+    }
+}
+
+impl From<HandshakeError> for PeerError {
+    fn from(e: HandshakeError) -> Self {
+        PeerError::Handshake(e)
+    }
+}
+
+impl From<MessageError> for PeerError {
+    fn from(e: MessageError) -> Self {
+        PeerError::Message(e)
+    }
+}
+
+/// What happened on the connection, for the download logic to act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Choked,
+    Unchoked,
+    Interested,
+    NotInterested,
+    Have(u32),
+    // The peer sent its full bitfield; see `PeerConnection::has_piece`.
+    Bitfield,
+    PieceReceived { index: u32, begin: u32, block: Vec<u8> },
+    BlockRequested { index: u32, begin: u32, length: u32 },
+    RequestCancelled { index: u32, begin: u32, length: u32 },
+    DhtPort(u16),
+}
+
+pub struct PeerConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    handshake: Handshake,
+    decoder: MessageDecoder,
+    num_pieces: u32,
+    peer_pieces: Vec<u8>,
+    // Set by the first message other than a keep-alive; a bitfield after that is a protocol error.
+    got_message: bool,
+
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+
+    last_sent: Instant,
+    last_received: Instant,
+    pub keep_alive_interval: Duration,
+    pub idle_timeout: Duration,
+}
+
+impl PeerConnection {
+    /// Connects and handshakes. `expected_peer_id` is the id the tracker gave for this peer, if any.
+    pub fn connect(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+        expected_peer_id: Option<&[u8]>,
+        num_pieces: u32,
+        timeout: Duration,
+    ) -> Result<PeerConnection, PeerError> {
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let handshake = perform_handshake(&mut stream, info_hash, peer_id, expected_peer_id)?;
+        PeerConnection::from_stream(stream, handshake, num_pieces)
+    }
+
+    /// Takes over a stream that already went through the handshake.
+    pub fn from_stream(stream: TcpStream, handshake: Handshake, num_pieces: u32) -> Result<PeerConnection, PeerError> {
+        let now = Instant::now();
+        Ok(PeerConnection {
+            addr: stream.peer_addr()?,
+            stream,
+            handshake,
+            decoder: MessageDecoder::new(),
+            num_pieces,
+            peer_pieces: vec![0; num_pieces.div_ceil(8) as usize],
+            got_message: false,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            last_sent: now,
+            last_received: now,
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            idle_timeout: IDLE_TIMEOUT,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// Whether we may request blocks right now.
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        index < self.num_pieces && self.peer_pieces[index as usize / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn send(&mut self, message: &PeerMessage) -> Result<(), PeerError> {
+        self.stream.write_all(&message.to_bytes())?;
+        self.last_sent = Instant::now();
+        match message {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sends a keep-alive if we have been quiet for `keep_alive_interval`, then waits up to `wait` for data and
+    /// returns the events it produced. An empty list means nothing arrived in time.
+    pub fn poll(&mut self, wait: Duration) -> Result<Vec<PeerEvent>, PeerError> {
+        if self.last_sent.elapsed() >= self.keep_alive_interval {
+            self.send(&PeerMessage::KeepAlive)?;
+        }
+
+        let mut events = self.drain_messages()?;
+        if !events.is_empty() {
+            return Ok(events);
+        }
+
+        // A zero read timeout means "block forever" to the OS.
+        self.stream.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+        let mut buf = [0u8; 16 * 1024];
+        match self.stream.read(&mut buf) {
+            Ok(0) => return Err(PeerError::Closed),
+            Ok(n) => {
+                self.last_received = Instant::now();
+                self.decoder.feed(&buf[..n]);
+                events = self.drain_messages()?;
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if self.last_received.elapsed() >= self.idle_timeout {
+            return Err(PeerError::IdleTimeout);
+        }
+        Ok(events)
+    }
+
+    fn drain_messages(&mut self) -> Result<Vec<PeerEvent>, PeerError> {
+        let mut events = Vec::new();
+        while let Some(message) = self.decoder.next_message()? {
+            if let Some(event) = self.handle(message)? {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    fn handle(&mut self, message: PeerMessage) -> Result<Option<PeerEvent>, PeerError> {
+        let first_message = !self.got_message;
+        if message != PeerMessage::KeepAlive {
+            self.got_message = true;
+        }
+
+        let event = match message {
+            PeerMessage::KeepAlive => return Ok(None),
+            PeerMessage::Choke => {
+                self.peer_choking = true;
+                PeerEvent::Choked
+            }
+            PeerMessage::Unchoke => {
+                self.peer_choking = false;
+                PeerEvent::Unchoked
+            }
+            PeerMessage::Interested => {
+                self.peer_interested = true;
+                PeerEvent::Interested
+            }
+            PeerMessage::NotInterested => {
+                self.peer_interested = false;
+                PeerEvent::NotInterested
+            }
+            PeerMessage::Have(index) => {
+                if index >= self.num_pieces {
+                    return Err(PeerError::InvalidPieceIndex(index));
+                }
+                self.peer_pieces[index as usize / 8] |= 0x80 >> (index % 8);
+                PeerEvent::Have(index)
+            }
+            PeerMessage::Bitfield(bits) => {
+                if !first_message {
+                    return Err(PeerError::UnexpectedBitfield);
+                }
+                if bits.len() != self.peer_pieces.len() {
+                    return Err(PeerError::InvalidBitfield { len: bits.len(), expected: self.peer_pieces.len() });
+                }
+                self.peer_pieces = bits;
+                // Spare bits at the end should be 0; don't let them count as pieces.
+                if !self.num_pieces.is_multiple_of(8)
+                    && let Some(last) = self.peer_pieces.last_mut()
+                {
+                    *last &= 0xFF << (8 - self.num_pieces % 8);
+                }
+                PeerEvent::Bitfield
+            }
+            PeerMessage::Piece { index, begin, block } => PeerEvent::PieceReceived { index, begin, block },
+            PeerMessage::Request { index, begin, length } => PeerEvent::BlockRequested { index, begin, length },
+            PeerMessage::Cancel { index, begin, length } => PeerEvent::RequestCancelled { index, begin, length },
+            PeerMessage::Port(port) => PeerEvent::DhtPort(port),
+        };
+        Ok(Some(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::HANDSHAKE_LEN;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    // A peer on localhost that answers the handshake and then runs `script` on its end of the socket.
+    fn remote_peer(script: impl FnOnce(TcpStream) + Send + 'static) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut theirs = [0u8; HANDSHAKE_LEN];
+            stream.read_exact(&mut theirs).unwrap();
+            stream.write_all(&Handshake::new(INFO_HASH, [2; 20]).to_bytes()).unwrap();
+            script(stream);
+        });
+        addr
+    }
+
+    fn connect(addr: SocketAddr, num_pieces: u32) -> PeerConnection {
+        PeerConnection::connect(addr, &INFO_HASH, &[3; 20], Some(&[2; 20]), num_pieces, Duration::from_secs(5)).unwrap()
+    }
+
+    fn read_message(stream: &mut TcpStream) -> PeerMessage {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut body).unwrap();
+        PeerMessage::decode(&body).unwrap()
+    }
+
+    // Polls until `count` events arrived.
+    fn events(conn: &mut PeerConnection, count: usize) -> Vec<PeerEvent> {
+        let mut all = Vec::new();
+        while all.len() < count {
+            all.extend(conn.poll(Duration::from_millis(100)).unwrap());
+        }
+        all
+    }
+
+    #[test]
+    fn tracks_state_and_reports_events() {
+        let addr = remote_peer(|mut stream| {
+            let mut out = Vec::new();
+            PeerMessage::Bitfield(vec![0b1000_0000, 0b0100_0000]).encode(&mut out);
+            PeerMessage::KeepAlive.encode(&mut out);
+            stream.write_all(&out).unwrap();
+
+            assert_eq!(read_message(&mut stream), PeerMessage::Interested);
+            let mut out = Vec::new();
+            PeerMessage::Unchoke.encode(&mut out);
+            PeerMessage::Have(2).encode(&mut out);
+            stream.write_all(&out).unwrap();
+
+            assert_eq!(read_message(&mut stream), PeerMessage::Request { index: 0, begin: 0, length: 4 });
+            stream.write_all(&PeerMessage::Piece { index: 0, begin: 0, block: b"data".to_vec() }.to_bytes()).unwrap();
+            stream.write_all(&PeerMessage::Choke.to_bytes()).unwrap();
+        });
+
+        let mut conn = connect(addr, 10);
+        assert_eq!(conn.handshake().peer_id, [2; 20]);
+        assert!(conn.am_choking() && !conn.am_interested() && conn.peer_choking() && !conn.peer_interested());
+
+        assert_eq!(events(&mut conn, 1), vec![PeerEvent::Bitfield]);
+        assert!(conn.has_piece(0) && conn.has_piece(9));
+        assert!(!conn.has_piece(2) && !conn.has_piece(10));
+
+        conn.send(&PeerMessage::Interested).unwrap();
+        assert!(conn.am_interested() && !conn.can_request());
+        assert_eq!(events(&mut conn, 2), vec![PeerEvent::Unchoked, PeerEvent::Have(2)]);
+        assert!(conn.can_request() && conn.has_piece(2));
+
+        conn.send(&PeerMessage::Request { index: 0, begin: 0, length: 4 }).unwrap();
+        assert_eq!(events(&mut conn, 2), vec![
+            PeerEvent::PieceReceived { index: 0, begin: 0, block: b"data".to_vec() },
+            PeerEvent::Choked,
+        ]);
+        assert!(conn.peer_choking());
+    }
+
+    #[test]
+    fn sends_keep_alives() {
+        let addr = remote_peer(|mut stream| {
+            assert_eq!(read_message(&mut stream), PeerMessage::KeepAlive);
+            stream.write_all(&PeerMessage::Unchoke.to_bytes()).unwrap();
+        });
+
+        let mut conn = connect(addr, 1);
+        conn.keep_alive_interval = Duration::ZERO;
+        assert_eq!(events(&mut conn, 1), vec![PeerEvent::Unchoked]);
+    }
+
+    #[test]
+    fn times_out_idle_peers() {
+        let addr = remote_peer(|stream| {
+            thread::sleep(Duration::from_secs(2));
+            drop(stream);
+        });
+
+        let mut conn = connect(addr, 1);
+        conn.idle_timeout = Duration::from_millis(50);
+        let result = loop {
+            match conn.poll(Duration::from_millis(20)) {
+                Ok(events) => assert!(events.is_empty()),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(result, PeerError::IdleTimeout));
+    }
+
+    #[test]
+    fn rejects_bad_piece_info() {
+        let addr = remote_peer(|mut stream| {
+            stream.write_all(&PeerMessage::Have(5).to_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        let mut conn = connect(addr, 5);
+        let result = loop {
+            match conn.poll(Duration::from_millis(100)) {
+                Ok(_) => continue,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(result, PeerError::InvalidPieceIndex(5)));
+
+        let addr = remote_peer(|mut stream| {
+            stream.write_all(&PeerMessage::Bitfield(vec![0; 3]).to_bytes()).unwrap();
+        });
+        let mut conn = connect(addr, 9);
+        let result = loop {
+            match conn.poll(Duration::from_millis(100)) {
+                Ok(_) => continue,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(result, PeerError::InvalidBitfield { len: 3, expected: 2 }));
+
+        // A bitfield after a 'have' would throw away what the 'have' told us.
+        let addr = remote_peer(|mut stream| {
+            let mut out = Vec::new();
+            PeerMessage::Have(1).encode(&mut out);
+            PeerMessage::Bitfield(vec![0; 1]).encode(&mut out);
+            stream.write_all(&out).unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        let mut conn = connect(addr, 8);
+        let result = loop {
+            match conn.poll(Duration::from_millis(100)) {
+                Ok(_) => continue,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(result, PeerError::UnexpectedBitfield));
+        assert!(conn.has_piece(1));
+    }
+}
@@ -0,0 +1,52 @@
+#![allow(dead_code)]
+
+// Our peer id, sent to trackers and in every handshake. Azureus style: '-', a two letter client code, a four digit
+// version, '-', then random bytes, eg. "-CR0100-" followed by 12 random bytes for corrent 0.1.0.
+// It is generated once per session; trackers and peers tell clients apart by it.
+
+use std::fmt;
+
+pub const DEFAULT_PREFIX: &[u8] = b"-CR0100-";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixTooLong(pub usize);
+
+impl fmt::Display for PrefixTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer id prefix is {} bytes, at most 20 fit", self.0)
+    }
+}
+
+impl std::error::Error for PrefixTooLong {}
+
+pub fn generate_peer_id() -> [u8; 20] {
+    generate_peer_id_with_prefix(DEFAULT_PREFIX).unwrap()
+}
+
+/// `prefix` followed by random bytes up to 20 bytes.
+pub fn generate_peer_id_with_prefix(prefix: &[u8]) -> Result<[u8; 20], PrefixTooLong> {
+    if prefix.len() > 20 {
+        return Err(PrefixTooLong(prefix.len()));
+    }
+    let mut id: [u8; 20] = rand::random();
+    id[..prefix.len()].copy_from_slice(prefix);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_prefixed_random_ids() {
+        let a = generate_peer_id();
+        let b = generate_peer_id();
+        assert_eq!(&a[..8], b"-CR0100-");
+        assert_ne!(a, b);
+
+        let custom = generate_peer_id_with_prefix(b"-XX2000-").unwrap();
+        assert_eq!(&custom[..8], b"-XX2000-");
+        assert_eq!(generate_peer_id_with_prefix(&[b'x'; 20]).unwrap(), [b'x'; 20]);
+        assert_eq!(generate_peer_id_with_prefix(&[0; 21]), Err(PrefixTooLong(21)));
+    }
+}
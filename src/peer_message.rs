@@ -0,0 +1,285 @@
+#![allow(dead_code)]
+
+// Peer wire messages (BEP 3), everything after the handshake. Each message is a 4 byte big-endian length, then
+// that many bytes: a 1 byte id and the payload. A length of 0 is a keep-alive and has no id.
+//
+// TCP delivers a byte stream, not messages, so MessageDecoder buffers whatever was read and hands out messages
+// once they are complete.
+
+use std::fmt;
+
+/// Big enough for a bitfield of ~16 million pieces or a 1 MiB block; real blocks are 16 KiB.
+pub const DEFAULT_MAX_MESSAGE_LEN: u32 = 2 * 1024 * 1024;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    // One bit per piece, high bit of the first byte is piece 0.
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    // DHT port (BEP 5).
+    Port(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    TooLarge { len: u32, max: u32 },
+    UnknownId(u8),
+    // The payload length doesn't fit the message, eg. a 'have' that isn't 4 bytes.
+    InvalidLength { id: u8, len: usize },
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::TooLarge { len, max } => write!(f, "message of {} bytes is over the {} byte limit", len, max),
+            MessageError::UnknownId(id) => write!(f, "unknown message id {}", id),
+            MessageError::InvalidLength { id, len } => write!(f, "message id {} can't have a {} byte payload", id, len),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+fn u32_at(payload: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(payload[at..at + 4].try_into().unwrap())
+}
+
+impl PeerMessage {
+    /// Appends the framed message, length prefix included.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let (id, ints, data): (u8, &[u32], &[u8]) = match self {
+            PeerMessage::KeepAlive => {
+                out.extend_from_slice(&0u32.to_be_bytes());
+                return;
+            }
+            PeerMessage::Choke => (CHOKE, &[], &[]),
+            PeerMessage::Unchoke => (UNCHOKE, &[], &[]),
+            PeerMessage::Interested => (INTERESTED, &[], &[]),
+            PeerMessage::NotInterested => (NOT_INTERESTED, &[], &[]),
+            PeerMessage::Have(index) => (HAVE, std::slice::from_ref(index), &[]),
+            PeerMessage::Bitfield(bits) => (BITFIELD, &[], bits),
+            PeerMessage::Request { index, begin, length } => (REQUEST, &[*index, *begin, *length], &[]),
+            PeerMessage::Piece { index, begin, block } => (PIECE, &[*index, *begin], block),
+            PeerMessage::Cancel { index, begin, length } => (CANCEL, &[*index, *begin, *length], &[]),
+            PeerMessage::Port(port) => {
+                out.extend_from_slice(&3u32.to_be_bytes());
+                out.push(PORT);
+                out.extend_from_slice(&port.to_be_bytes());
+                return;
+            }
+        };
+        let len = 1 + 4 * ints.len() + data.len();
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+        out.push(id);
+        for i in ints {
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+        out.extend_from_slice(data);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    /// Parses one message body: the id and payload, without the length prefix.
+    pub fn decode(body: &[u8]) -> Result<PeerMessage, MessageError> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let expect = |len: usize| {
+            if payload.len() == len { Ok(()) } else { Err(MessageError::InvalidLength { id, len: payload.len() }) }
+        };
+
+        let message = match id {
+            CHOKE => expect(0).map(|_| PeerMessage::Choke)?,
+            UNCHOKE => expect(0).map(|_| PeerMessage::Unchoke)?,
+            INTERESTED => expect(0).map(|_| PeerMessage::Interested)?,
+            NOT_INTERESTED => expect(0).map(|_| PeerMessage::NotInterested)?,
+            HAVE => expect(4).map(|_| PeerMessage::Have(u32_at(payload, 0)))?,
+            BITFIELD => PeerMessage::Bitfield(payload.to_vec()),
+            REQUEST => expect(12).map(|_| PeerMessage::Request {
+                index: u32_at(payload, 0),
+                begin: u32_at(payload, 4),
+                length: u32_at(payload, 8),
+            })?,
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(MessageError::InvalidLength { id, len: payload.len() });
+                }
+                PeerMessage::Piece { index: u32_at(payload, 0), begin: u32_at(payload, 4), block: payload[8..].to_vec() }
+            }
+            CANCEL => expect(12).map(|_| PeerMessage::Cancel {
+                index: u32_at(payload, 0),
+                begin: u32_at(payload, 4),
+                length: u32_at(payload, 8),
+            })?,
+            PORT => expect(2).map(|_| PeerMessage::Port(u16::from_be_bytes([payload[0], payload[1]])))?,
+            _ => return Err(MessageError::UnknownId(id)),
+        };
+        Ok(message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageDecoder {
+    buf: Vec<u8>,
+    // Start of the first byte not yet handed out. Consumed bytes are only dropped from `buf` on the next
+    // `feed`, so taking many small messages out of one big read doesn't shift the buffer each time.
+    pos: usize,
+    max_message_len: u32,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        MessageDecoder::new()
+    }
+}
+
+impl MessageDecoder {
+    pub fn new() -> MessageDecoder {
+        MessageDecoder::with_max_message_len(DEFAULT_MAX_MESSAGE_LEN)
+    }
+
+    /// Messages longer than `max_message_len` (length prefix not counted) are errors, so a peer can't make us
+    /// buffer gigabytes by sending a huge length.
+    pub fn with_max_message_len(max_message_len: u32) -> MessageDecoder {
+        MessageDecoder { buf: Vec::new(), pos: 0, max_message_len }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Bytes received but not yet returned as messages.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// The next complete message, or None until more bytes are fed. After an error the stream is out of sync and
+    /// the connection should be dropped.
+    pub fn next_message(&mut self) -> Result<Option<PeerMessage>, MessageError> {
+        let pending = &self.buf[self.pos..];
+        if pending.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32_at(pending, 0);
+        if len > self.max_message_len {
+            return Err(MessageError::TooLarge { len, max: self.max_message_len });
+        }
+        let end = 4 + len as usize;
+        if pending.len() < end {
+            return Ok(None);
+        }
+        let message = PeerMessage::decode(&pending[4..end]);
+        self.pos += end;
+        message.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_messages() -> Vec<PeerMessage> {
+        vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have(0xDEADBEEF),
+            PeerMessage::Bitfield(vec![0b1010_0000, 0xFF]),
+            PeerMessage::Request { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Piece { index: 2, begin: 0, block: b"block data".to_vec() },
+            PeerMessage::Cancel { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Port(6881),
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        for message in all_messages() {
+            let mut decoder = MessageDecoder::new();
+            decoder.feed(&message.to_bytes());
+            assert_eq!(decoder.next_message().unwrap(), Some(message.clone()), "{:?}", message);
+            assert_eq!(decoder.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn encodes_wire_format() {
+        assert_eq!(PeerMessage::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+        assert_eq!(PeerMessage::Interested.to_bytes(), [0, 0, 0, 1, 2]);
+        assert_eq!(PeerMessage::Have(5).to_bytes(), [0, 0, 0, 5, 4, 0, 0, 0, 5]);
+        assert_eq!(
+            PeerMessage::Request { index: 1, begin: 2, length: 3 }.to_bytes(),
+            [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+        assert_eq!(PeerMessage::Port(0x1AE1).to_bytes(), [0, 0, 0, 3, 9, 0x1A, 0xE1]);
+    }
+
+    #[test]
+    fn decodes_incrementally() {
+        let stream = all_messages().iter().flat_map(|m| m.to_bytes()).collect::<Vec<u8>>();
+        let mut decoder = MessageDecoder::new();
+        let mut decoded = Vec::new();
+        // One byte at a time is the worst case for partial messages.
+        for byte in stream {
+            decoder.feed(&[byte]);
+            while let Some(message) = decoder.next_message().unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, all_messages());
+
+        // And all at once, with a partial message left over for the next read.
+        let stream = all_messages().iter().flat_map(|m| m.to_bytes()).collect::<Vec<u8>>();
+        let mut decoder = MessageDecoder::new();
+        decoder.feed(&stream);
+        decoder.feed(&[0, 0, 0, 5, HAVE, 0]);
+        let mut decoded = Vec::new();
+        while let Some(message) = decoder.next_message().unwrap() {
+            decoded.push(message);
+        }
+        assert_eq!(decoded, all_messages());
+        assert_eq!(decoder.buffered(), 6);
+        decoder.feed(&[0, 0, 7]);
+        assert_eq!(decoder.next_message().unwrap(), Some(PeerMessage::Have(7)));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        let mut decoder = MessageDecoder::with_max_message_len(16);
+        decoder.feed(&[0, 0, 0, 17]);
+        assert_eq!(decoder.next_message(), Err(MessageError::TooLarge { len: 17, max: 16 }));
+
+        assert_eq!(PeerMessage::decode(&[42]), Err(MessageError::UnknownId(42)));
+        assert_eq!(PeerMessage::decode(&[HAVE, 0, 0]), Err(MessageError::InvalidLength { id: HAVE, len: 2 }));
+        assert_eq!(PeerMessage::decode(&[CHOKE, 0]), Err(MessageError::InvalidLength { id: CHOKE, len: 1 }));
+        assert_eq!(PeerMessage::decode(&[PIECE, 0, 0, 0, 0]), Err(MessageError::InvalidLength { id: PIECE, len: 4 }));
+    }
+}
@@ -0,0 +1,172 @@
+#![allow(dead_code)]
+
+// Peer lists from tracker responses. Trackers send 'peers' either as a list of dictionaries
+// (ip, peer id, port) or, since BEP 23, as a compact string of 6 bytes per peer: 4 for the IPv4 address and
+// 2 for the port, both big-endian. Compact peers carry no peer id.
+// IPv6 peers come in 'peers6' (BEP 7), compact only: 16 bytes of address and 2 of port.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::bencode::BencodeRef;
+use crate::bencode_query::{BencodeQuery, QueryError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub peer_id: Option<Vec<u8>>, // 20 byte peer id. Not sent in the compact form.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeersError {
+    MissingPeers,
+    // 'peers' is neither a list nor a string, or 'peers6' is not a string.
+    InvalidPeers { field: &'static str, found: &'static str },
+    InvalidCompactLength { field: &'static str, len: usize },
+}
+
+impl fmt::Display for PeersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeersError::MissingPeers => write!(f, "tracker response does not have a field named 'peers'"),
+            PeersError::InvalidPeers { field, found } => write!(f, "'{}' is {}, which is not a peer list", field, found),
+            PeersError::InvalidCompactLength { field, len } => {
+                write!(f, "compact '{}' is {} bytes, not a multiple of {}", field, len, compact_peer_size(field))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PeersError {}
+
+fn compact_peer_size(field: &str) -> usize {
+    if field == "peers6" { 18 } else { 6 }
+}
+
+/// `None` if the entry is malformed. The spec allows a DNS name in 'ip'; we only take IP literals.
+pub fn get_info_from_peer_dict(peer: &BencodeRef) -> Result<Option<PeerInfo>, QueryError> {
+    let Ok(ip) = peer.get_str_at("ip")?.parse::<IpAddr>() else {
+        return Ok(None);
+    };
+    Ok(Some(PeerInfo {
+        addr: SocketAddr::new(ip, peer.get_u16_at("port")?),
+        peer_id: peer.get_opt_bytes_at("peer id")?.map(<[u8]>::to_vec),
+    }))
+}
+
+pub fn parse_compact_peers(peers: &[u8]) -> Result<Vec<PeerInfo>, PeersError> {
+    if !peers.len().is_multiple_of(6) {
+        return Err(PeersError::InvalidCompactLength { field: "peers", len: peers.len() });
+    }
+    Ok(peers
+        .chunks_exact(6)
+        .map(|p| PeerInfo {
+            addr: SocketAddr::new(Ipv4Addr::new(p[0], p[1], p[2], p[3]).into(), u16::from_be_bytes([p[4], p[5]])),
+            peer_id: None,
+        })
+        .collect())
+}
+
+pub fn parse_compact_peers6(peers: &[u8]) -> Result<Vec<PeerInfo>, PeersError> {
+    if !peers.len().is_multiple_of(18) {
+        return Err(PeersError::InvalidCompactLength { field: "peers6", len: peers.len() });
+    }
+    Ok(peers
+        .chunks_exact(18)
+        .map(|p| {
+            let ip: [u8; 16] = p[..16].try_into().unwrap();
+            PeerInfo { addr: SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([p[16], p[17]])), peer_id: None }
+        })
+        .collect())
+}
+
+/// Peers from 'peers' in either form followed by 'peers6'. Malformed entries in the dictionary form are skipped.
+pub fn get_all_peers_info(tracker_response: &BTreeMap<&[u8], BencodeRef>) -> Result<Vec<PeerInfo>, PeersError> {
+    let peers6 = match tracker_response.get(&b"peers6"[..]) {
+        Some(BencodeRef::ByteString(peers)) => Some(parse_compact_peers6(peers)?),
+        Some(other) => return Err(PeersError::InvalidPeers { field: "peers6", found: other.type_name() }),
+        None => None,
+    };
+
+    let mut all = match tracker_response.get(&b"peers"[..]) {
+        Some(BencodeRef::List(peers)) => {
+            peers.iter().filter_map(|peer| get_info_from_peer_dict(peer).ok().flatten()).collect()
+        }
+        Some(BencodeRef::ByteString(peers)) => parse_compact_peers(peers)?,
+        Some(other) => return Err(PeersError::InvalidPeers { field: "peers", found: other.type_name() }),
+        // An IPv6-only tracker may leave out 'peers'.
+        None if peers6.is_some() => Vec::new(),
+        None => return Err(PeersError::MissingPeers),
+    };
+    all.extend(peers6.unwrap_or_default());
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bdecode::bdecode_ref;
+
+    fn response(bytes: &[u8]) -> BTreeMap<&[u8], BencodeRef<'_>> {
+        let BencodeRef::Dictionary(d) = bdecode_ref(bytes).unwrap() else { panic!("not a dictionary") };
+        d
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let mut bytes = b"d5:peers12:".to_vec();
+        bytes.extend_from_slice(&[192, 168, 1, 2, 0x1A, 0xE1, 10, 0, 0, 1, 0, 80]);
+        bytes.push(b'e');
+
+        assert_eq!(get_all_peers_info(&response(&bytes)).unwrap(), vec![
+            PeerInfo { addr: "192.168.1.2:6881".parse().unwrap(), peer_id: None },
+            PeerInfo { addr: "10.0.0.1:80".parse().unwrap(), peer_id: None },
+        ]);
+        assert_eq!(parse_compact_peers(&[1, 2, 3]), Err(PeersError::InvalidCompactLength { field: "peers", len: 3 }));
+    }
+
+    #[test]
+    fn parses_peers6() {
+        let mut bytes = b"d5:peers6:".to_vec();
+        bytes.extend_from_slice(&[127, 0, 0, 1, 0, 1]);
+        bytes.extend_from_slice(b"6:peers618:");
+        bytes.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1A, 0xE1]);
+        bytes.push(b'e');
+
+        assert_eq!(get_all_peers_info(&response(&bytes)).unwrap(), vec![
+            PeerInfo { addr: "127.0.0.1:1".parse().unwrap(), peer_id: None },
+            PeerInfo { addr: "[2001:db8::1]:6881".parse().unwrap(), peer_id: None },
+        ]);
+
+        // peers6 on its own is fine.
+        let mut bytes = b"d6:peers618:".to_vec();
+        bytes.extend_from_slice(&[0; 18]);
+        bytes.push(b'e');
+        assert_eq!(get_all_peers_info(&response(&bytes)).unwrap(), vec![PeerInfo { addr: "[::]:0".parse().unwrap(), peer_id: None }]);
+
+        assert_eq!(parse_compact_peers6(&[0; 6]), Err(PeersError::InvalidCompactLength { field: "peers6", len: 6 }));
+    }
+
+    #[test]
+    fn parses_dictionary_peers() {
+        let peers = get_all_peers_info(&response(
+            b"d5:peersld2:ip9:127.0.0.17:peer id20:abcdefghijklmnopqrst4:porti6881eed2:ip3:::14:porti1eed4:porti2eeee",
+        ))
+        .unwrap();
+        assert_eq!(peers, vec![
+            PeerInfo { addr: "127.0.0.1:6881".parse().unwrap(), peer_id: Some(b"abcdefghijklmnopqrst".to_vec()) },
+            PeerInfo { addr: "[::1]:1".parse().unwrap(), peer_id: None },
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_peers() {
+        assert_eq!(get_all_peers_info(&response(b"de")), Err(PeersError::MissingPeers));
+        assert_eq!(get_all_peers_info(&response(b"d5:peersi1ee")), Err(PeersError::InvalidPeers { field: "peers", found: "an integer" }));
+        assert_eq!(
+            get_all_peers_info(&response(b"d5:peers0:6:peers6lee")).unwrap_err().to_string(),
+            "'peers6' is a list, which is not a peer list"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+#![allow(dead_code)]
+
+// Maps the torrent's single piece/byte space onto the files on disk.
+//
+// Pieces don't care about file boundaries: all files are laid end to end in the order of info.files and cut
+// into piece_length chunks, so one piece can end one file and start the next (or span many small ones).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::metainfo::Info;
+
+/// A contiguous part of a byte range that lives in a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    /// Offset inside the file.
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone)]
+struct StoredFile {
+    path: PathBuf,
+    length: u64,
+    // Offset of the file's first byte in the torrent's byte space.
+    start: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Storage {
+    files: Vec<StoredFile>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl Storage {
+    /// Files are placed at `root/name` for single-file torrents and `root/name/<path...>` for multi-file ones.
+    /// Path components were already checked for '..' and separators when the metainfo was parsed.
+    pub fn new(root: impl AsRef<Path>, info: &Info) -> Storage {
+        let base = root.as_ref().join(&info.name);
+        let mut start = 0;
+        let files = info
+            .files
+            .iter()
+            .map(|f| {
+                let file = StoredFile { path: f.path.iter().fold(base.clone(), |p, c| p.join(c)), length: f.length, start };
+                start += f.length;
+                file
+            })
+            .collect();
+        Storage { files, piece_length: info.piece_length, total_length: info.length }
+    }
+
+    pub fn file_path(&self, file_index: usize) -> &Path {
+        &self.files[file_index].path
+    }
+
+    /// Splits the global byte range [offset, offset + length) into per-file slices, in order.
+    /// Zero-length files never appear. Ranges past the end of the torrent are cut off.
+    pub fn map_range(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let end = offset.saturating_add(length).min(self.total_length);
+        // First file that ends after `offset`.
+        let first = self.files.partition_point(|f| f.start + f.length <= offset);
+
+        let mut slices = Vec::new();
+        for (file_index, file) in self.files.iter().enumerate().skip(first) {
+            if file.start >= end {
+                break;
+            }
+            let from = offset.max(file.start);
+            let to = end.min(file.start + file.length);
+            if to > from {
+                slices.push(FileSlice { file_index, offset: from - file.start, length: to - from });
+            }
+        }
+        slices
+    }
+
+    fn piece_range(&self, index: usize) -> io::Result<(u64, u64)> {
+        let offset = index as u64 * self.piece_length;
+        if offset >= self.total_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("piece {} is out of range", index)));
+        }
+        Ok((offset, self.piece_length.min(self.total_length - offset)))
+    }
+
+    pub fn piece_slices(&self, index: usize) -> io::Result<Vec<FileSlice>> {
+        let (offset, length) = self.piece_range(index)?;
+        Ok(self.map_range(offset, length))
+    }
+
+    /// Creates every file and its directories and extends each to its full length, leaving any data already
+    /// there alone. Call this before downloading: no piece maps onto a zero-length file, so write_piece would
+    /// never create one.
+    pub fn allocate(&self) -> io::Result<()> {
+        for file_index in 0..self.files.len() {
+            let file = self.open_for_write(file_index)?;
+            if file.metadata()?.len() < self.files[file_index].length {
+                file.set_len(self.files[file_index].length)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a whole, already verified piece. Missing directories and files are created.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> io::Result<()> {
+        let (offset, length) = self.piece_range(index)?;
+        if data.len() as u64 != length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("piece {} is {} bytes, got {}", index, length, data.len()),
+            ));
+        }
+
+        let mut data = data;
+        for slice in self.map_range(offset, length) {
+            let mut file = self.open_for_write(slice.file_index)?;
+            file.seek(SeekFrom::Start(slice.offset))?;
+            let (chunk, rest) = data.split_at(slice.length as usize);
+            file.write_all(chunk)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    pub fn read_piece(&self, index: usize) -> io::Result<Vec<u8>> {
+        let (offset, length) = self.piece_range(index)?;
+        let mut piece = vec![0u8; length as usize];
+        let mut pos = 0;
+        for slice in self.map_range(offset, length) {
+            let mut file = File::open(&self.files[slice.file_index].path)?;
+            file.seek(SeekFrom::Start(slice.offset))?;
+            file.read_exact(&mut piece[pos..pos + slice.length as usize])?;
+            pos += slice.length as usize;
+        }
+        Ok(piece)
+    }
+
+    fn open_for_write(&self, file_index: usize) -> io::Result<File> {
+        let path = &self.files[file_index].path;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // No truncate: other pieces of the same file may already be on disk.
+        OpenOptions::new().create(true).truncate(false).write(true).open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::FileEntry;
+
+    fn info(piece_length: u64, lengths: &[u64]) -> Info {
+        let files: Vec<FileEntry> = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &length)| FileEntry { length, path: vec!["sub".to_string(), format!("{}.bin", i)], md5sum: None })
+            .collect();
+        let length = lengths.iter().sum::<u64>();
+        Info {
+            name: "dir".to_string(),
+            piece_length,
+            pieces: vec![[0u8; 20]; length.div_ceil(piece_length) as usize],
+            length,
+            files,
+            is_multi_file: true,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("corrent-storage-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn maps_ranges_across_files() {
+        let storage = Storage::new("/downloads", &info(4, &[3, 0, 6, 1]));
+        assert_eq!(storage.file_path(2), Path::new("/downloads/dir/sub/2.bin"));
+
+        assert_eq!(storage.piece_slices(0).unwrap(), vec![
+            FileSlice { file_index: 0, offset: 0, length: 3 },
+            FileSlice { file_index: 2, offset: 0, length: 1 },
+        ]);
+        assert_eq!(storage.piece_slices(1).unwrap(), vec![FileSlice { file_index: 2, offset: 1, length: 4 }]);
+        // The last piece is short.
+        assert_eq!(storage.piece_slices(2).unwrap(), vec![
+            FileSlice { file_index: 2, offset: 5, length: 1 },
+            FileSlice { file_index: 3, offset: 0, length: 1 },
+        ]);
+        assert!(storage.piece_slices(3).is_err());
+        assert_eq!(storage.map_range(8, 100), storage.piece_slices(2).unwrap());
+    }
+
+    #[test]
+    fn writes_and_reads_pieces() {
+        let root = temp_dir("multi");
+        let storage = Storage::new(&root, &info(4, &[3, 0, 6, 1]));
+        storage.allocate().unwrap();
+        assert_eq!(fs::read(root.join("dir/sub/2.bin")).unwrap(), [0; 6]);
+
+        // Out of order, like a real download.
+        storage.write_piece(2, b"ij").unwrap();
+        storage.write_piece(0, b"abcd").unwrap();
+        storage.write_piece(1, b"efgh").unwrap();
+        assert!(storage.write_piece(1, b"short").is_err());
+
+        assert_eq!(fs::read(root.join("dir/sub/0.bin")).unwrap(), b"abc");
+        assert_eq!(fs::read(root.join("dir/sub/1.bin")).unwrap(), b"");
+        assert_eq!(fs::read(root.join("dir/sub/2.bin")).unwrap(), b"defghi");
+        assert_eq!(fs::read(root.join("dir/sub/3.bin")).unwrap(), b"j");
+        assert_eq!(storage.read_piece(0).unwrap(), b"abcd");
+        assert_eq!(storage.read_piece(2).unwrap(), b"ij");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn single_file_goes_to_root_name() {
+        let root = temp_dir("single");
+        let mut single = info(4, &[5]);
+        single.files[0].path.clear();
+        single.is_multi_file = false;
+        single.name = "file.bin".to_string();
+
+        let storage = Storage::new(&root, &single);
+        storage.write_piece(1, b"e").unwrap();
+        // Allocating after a write keeps what is already on disk.
+        storage.allocate().unwrap();
+        storage.write_piece(0, b"abcd").unwrap();
+        assert_eq!(fs::read(root.join("file.bin")).unwrap(), b"abcde");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,92 +1,557 @@
-
-use hex_literal::hex;
-use sha1::{Sha1, Digest};
 use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, UdpSocket};
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+
+
+use crate::bdecode::{bdecode_detailed, BdecodingError, DecodeOptions, DecodeWarning};
+use crate::bencode_query::{BencodeQuery, QueryError};
+use crate::metainfo::Metainfo;
+use crate::peers::{PeerInfo, PeersError, get_all_peers_info};
+use crate::udp_tracker::UdpTrackerClient;
+
+
+fn escape_hash_to_string(hash: &[u8]) -> String {
+    hash.iter()
+        .map(|b| format!("%{:02X}", b))
+        .collect::<String>()
+}
 
-use reqwest;
+// Adds `name=value` to `url`, which may already have a query string of its own (eg. a private tracker's
+// "?passkey=..."), possibly ending in '?' or '&'. `value` must already be escaped.
+fn append_query_param(url: &mut String, name: &str, value: &str) {
+    if !url.contains('?') {
+        url.push('?');
+    } else if !url.ends_with('?') && !url.ends_with('&') {
+        url.push('&');
+    }
+    url.push_str(name);
+    url.push('=');
+    url.push_str(value);
+}
 
-use crate::bencode::{BencodeValue, bencode_element};
-use crate::bdecode::bdecode_element;
+// BEP 12: tiers are tried in order and trackers within a tier are tried in order. The order inside each tier is
+// shuffled once per session, and a tracker that answers moves to the front of its tier so it is tried first next time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
 
+impl TrackerTiers {
+    /// Keeps the given order. Call `shuffle` to randomize within tiers as the spec asks.
+    pub fn new(tiers: Vec<Vec<String>>) -> TrackerTiers {
+        TrackerTiers { tiers: tiers.into_iter().filter(|t| !t.is_empty()).collect() }
+    }
 
-fn get_info(torrent: &BTreeMap<Vec<u8>, BencodeValue>) -> &BencodeValue {
-    match torrent.get(&b"info"[..]){
-        Some(info) => info,
-        None => {
-            eprintln!("ERROR: Torrent file does not contain 'info' field.");
-            std::process::exit(1);
+    pub fn shuffle(&mut self) {
+        let mut rng = rand::rng();
+        for tier in &mut self.tiers {
+            tier.shuffle(&mut rng);
         }
-    } 
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Calls `announce` on each tracker in order until one succeeds, then promotes that tracker within its tier.
+    /// If every tracker fails, all the errors are returned in the order they happened.
+    pub fn try_each<T, E>(&mut self, mut announce: impl FnMut(&str) -> Result<T, E>) -> Result<T, AllTrackersFailed<E>> {
+        let mut errors = Vec::new();
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                match announce(&tier[i]) {
+                    Ok(response) => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(response);
+                    }
+                    Err(e) => errors.push((tier[i].clone(), e)),
+                }
+            }
+        }
+        Err(AllTrackersFailed(errors))
+    }
 }
 
-// TODO: Add support for multi-tracker urls.
-fn get_announce_url(torrent: &BTreeMap<Vec<u8>, BencodeValue>) -> String {
-    match torrent.get(&b"announce"[..]){
-        Some(announce) => {
-            let BencodeValue::ByteString(announce_bytes) = announce else {
-                eprintln!("ERROR: 'announce' field is not a bytestring.");
-                std::process::exit(1);
-            };
-            String::from_utf8(announce_bytes.to_vec()).expect("ERROR: 'announce' field is not UTF-8 valid.")
+#[derive(Debug)]
+pub struct AllTrackersFailed<E>(pub Vec<(String, E)>);
+
+impl<E: fmt::Display> fmt::Display for AllTrackersFailed<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "no trackers to announce to");
+        }
+        write!(f, "every tracker failed:")?;
+        for (url, e) in &self.0 {
+            write!(f, "\n  {}: {}", url, e)?;
         }
-        None => {
-            eprintln!("ERROR: Torrent file does not contain 'announce' field.");
-            std::process::exit(1);
-        } 
-    } 
+        Ok(())
+    }
 }
 
-fn escape_hash_to_string(hash: &Vec<u8>) -> String {
-    hash.iter()
-        .map(|b| format!("%{:02X}", b))
-        .collect::<String>()
+impl<E: fmt::Debug + fmt::Display> std::error::Error for AllTrackersFailed<E> {}
+
+// The address the OS would use to reach the IPv6 internet. Connecting a UDP socket sends no packets.
+pub fn local_ipv6_addr() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:80").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if !ip.is_loopback() && !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+// Everything a client can tell an HTTP tracker. Optional fields are left out of the URL when None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    /// None for the regular announces sent every interval.
+    pub event: Option<AnnounceEvent>,
+    pub compact: bool,
+    /// Ignored by trackers when `compact` is set.
+    pub no_peer_id: bool,
+    pub numwant: Option<u32>,
+    pub key: Option<String>,
+    /// Echo of the 'tracker id' from a previous response.
+    pub trackerid: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// BEP 7: lets a dual-stack tracker hand our IPv6 address to other peers.
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl AnnounceRequest {
+    /// A first announce for a fresh download: nothing transferred yet and the whole torrent left.
+    pub fn new(metainfo: &Metainfo, peer_id: [u8; 20], port: u16) -> AnnounceRequest {
+        AnnounceRequest { left: metainfo.info.length, ..AnnounceRequest::for_info_hash(metainfo.info_hash, peer_id, port) }
+    }
+
+    /// A `started` announce with every counter at 0 and no optional parameters. Set the other fields with
+    /// struct update syntax.
+    pub fn for_info_hash(info_hash: [u8; 20], peer_id: [u8; 20], port: u16) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: Some(AnnounceEvent::Started),
+            compact: true,
+            no_peer_id: false,
+            numwant: None,
+            key: None,
+            trackerid: None,
+            ip: None,
+            ipv6: None,
+        }
+    }
+
+    /// Appends the parameters to `announce`, which may already have a query string of its own.
+    pub fn to_url(&self, announce: &str) -> String {
+        let mut url = announce.to_string();
+        let mut add = |name: &str, value: &str| append_query_param(&mut url, name, value);
+
+        add("info_hash", &escape_hash_to_string(&self.info_hash));
+        add("peer_id", &escape_hash_to_string(&self.peer_id));
+        add("port", &self.port.to_string());
+        add("uploaded", &self.uploaded.to_string());
+        add("downloaded", &self.downloaded.to_string());
+        add("left", &self.left.to_string());
+        add("compact", if self.compact { "1" } else { "0" });
+        if self.no_peer_id {
+            add("no_peer_id", "1");
+        }
+        if let Some(event) = self.event {
+            add("event", event.as_str());
+        }
+        if let Some(numwant) = self.numwant {
+            add("numwant", &numwant.to_string());
+        }
+        if let Some(key) = &self.key {
+            add("key", &escape_hash_to_string(key.as_bytes()));
+        }
+        if let Some(trackerid) = &self.trackerid {
+            add("trackerid", &escape_hash_to_string(trackerid.as_bytes()));
+        }
+        if let Some(ip) = self.ip {
+            add("ip", &escape_hash_to_string(ip.to_string().as_bytes()));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            add("ipv6", &escape_hash_to_string(ipv6.to_string().as_bytes()));
+        }
+        url
+    }
+}
+
+#[derive(Debug)]
+pub enum TrackerError {
+    Http(reqwest::Error),
+    Io(io::Error),
+    InvalidUrl(String),
+    // No reply after every retransmission (UDP trackers).
+    Timeout,
+    // A reply that doesn't follow the protocol, eg. too short or for the wrong action (UDP trackers).
+    InvalidResponse(String),
+    // The announce URL doesn't follow the announce -> scrape naming convention.
+    ScrapeUnsupported(String),
+    Decode(BdecodingError),
+    // The response is bencode but not a dictionary.
+    NotADictionary,
+    // The tracker refused the announce and said why in 'failure reason'.
+    Failure(String),
+    Field(QueryError),
+    Peers(PeersError),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Http(e) => write!(f, "request failed: {}", e),
+            TrackerError::Io(e) => write!(f, "request failed: {}", e),
+            TrackerError::InvalidUrl(url) => write!(f, "invalid tracker URL {:?}", url),
+            TrackerError::Timeout => write!(f, "tracker did not answer"),
+            TrackerError::InvalidResponse(reason) => write!(f, "invalid response: {}", reason),
+            TrackerError::ScrapeUnsupported(url) => write!(f, "tracker {:?} does not support scrape", url),
+            TrackerError::Decode(e) => write!(f, "response is not valid bencode: {}", e),
+            TrackerError::NotADictionary => write!(f, "response is not a dictionary"),
+            TrackerError::Failure(reason) => write!(f, "tracker refused the announce: {}", reason),
+            TrackerError::Field(e) => write!(f, "response field error: {}", e),
+            TrackerError::Peers(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> Self {
+        TrackerError::Http(e)
+    }
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        TrackerError::Io(e)
+    }
 }
 
-fn get_hash(bytes: &Vec<u8>) -> Vec<u8> {
-    // Create SHA-1 hasher object.
-    let mut hasher = Sha1::new();
+impl From<BdecodingError> for TrackerError {
+    fn from(e: BdecodingError) -> Self {
+        TrackerError::Decode(e)
+    }
+}
 
-    // process input message
-    hasher.update(&bytes);
+impl From<QueryError> for TrackerError {
+    fn from(e: QueryError) -> Self {
+        TrackerError::Field(e)
+    }
+}
 
-    // acquire hash digest in the form of GenericArray and then convert to Vec,
-    hasher.finalize().to_vec()
+impl From<PeersError> for TrackerError {
+    fn from(e: PeersError) -> Self {
+        TrackerError::Peers(e)
+    }
 }
 
-pub fn get_info_hash(torrent: &BTreeMap<Vec<u8>, BencodeValue>) -> Vec<u8> {
-    let info = get_info(&torrent);
-    get_hash(&bencode_element(&info))
-} 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    /// How long to wait before the next regular announce.
+    pub interval: Duration,
+    /// Announcing more often than this gets a client ignored or banned.
+    pub min_interval: Option<Duration>,
+    /// To be sent back as 'trackerid' in later announces.
+    pub tracker_id: Option<String>,
+    /// Number of seeders.
+    pub complete: Option<u64>,
+    /// Number of leechers.
+    pub incomplete: Option<u64>,
+    /// The announce went through but the tracker has something to say.
+    pub warning_message: Option<String>,
+    pub peers: Vec<PeerInfo>,
+}
 
-fn get_random_20byte_hash() -> Vec<u8> {
-    get_hash(&b"Dragonado is the goat".to_vec())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Number of seeders.
+    pub complete: u64,
+    /// Number of times the torrent was downloaded to completion.
+    pub downloaded: u64,
+    /// Number of leechers.
+    pub incomplete: u64,
 }
 
-fn get_tracker_request_url(torrent: &BTreeMap<Vec<u8>, BencodeValue>) -> String {
-    let mut url = get_announce_url(&torrent);
+impl AnnounceResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+        Ok(AnnounceResponse::from_bytes_with_warnings(bytes)?.0)
+    }
+
+    /// Trackers don't always emit canonical bencode, so responses are decoded leniently.
+    pub fn from_bytes_with_warnings(bytes: &[u8]) -> Result<(AnnounceResponse, Vec<DecodeWarning>), TrackerError> {
+        let decoded = bdecode_detailed(bytes, &[], DecodeOptions::lenient())?;
+        let response = &decoded.value;
+        let Some(dict) = response.as_dictionary() else {
+            return Err(TrackerError::NotADictionary);
+        };
+
+        // A failed announce may have nothing else in it, so this is checked first.
+        if let Some(reason) = response.get_opt_text_at("failure reason")? {
+            return Err(TrackerError::Failure(reason));
+        }
 
-    url = url + "?info_hash=" + &escape_hash_to_string(&get_info_hash(&torrent)); 
-    url = url + "&peer_id=" + &escape_hash_to_string(&get_random_20byte_hash());
-    url = url + "&port=6881";
-    // TODO: Add more fields like byes downloaded, uploaded, etc,.
-    url
+        let announce = AnnounceResponse {
+            interval: Duration::from_secs(response.get_u64_at("interval")?),
+            min_interval: response.get_opt_u64_at("min interval")?.map(Duration::from_secs),
+            tracker_id: response.get_opt_text_at("tracker id")?,
+            complete: response.get_opt_u64_at("complete")?,
+            incomplete: response.get_opt_u64_at("incomplete")?,
+            warning_message: response.get_opt_text_at("warning message")?,
+            peers: get_all_peers_info(dict)?,
+        };
+        Ok((announce, decoded.warnings))
+    }
 }
 
-pub fn get_tracker_response(torrent: &BTreeMap<Vec<u8>, BencodeValue>) -> Result<BencodeValue, Box<dyn std::error::Error>> {
-    let get_tracker_request_url = get_tracker_request_url(&torrent);
-    // TODO: Handle the case when the tracker returns a compact format response.
-    let response = reqwest::blocking::get(get_tracker_request_url)?.bytes()?;   
-    let decoded_response = bdecode_element(&response)?;
+// Hashes per HTTP scrape request, to keep the URL a sane length.
+const HTTP_SCRAPE_BATCH: usize = 50;
+
+/// The scrape URL for an HTTP announce URL: the last path segment must start with "announce", which is replaced by
+/// "scrape". Eg. http://t/x/announce.php?k=1 -> http://t/x/scrape.php?k=1. None if the tracker can't be scraped.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let path_end = announce.find('?').unwrap_or(announce.len());
+    let last_slash = announce[..path_end].rfind('/')?;
+    let rest = announce[last_slash + 1..].strip_prefix("announce")?;
+    Some(format!("{}scrape{}", &announce[..=last_slash], rest))
+}
+
+/// Parses the 'files' dictionary of an HTTP scrape response. Trackers leave out hashes they don't know.
+pub fn parse_scrape_response(bytes: &[u8]) -> Result<BTreeMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let decoded = bdecode_detailed(bytes, &[], DecodeOptions::lenient())?;
+    let response = &decoded.value;
+    if response.as_dictionary().is_none() {
+        return Err(TrackerError::NotADictionary);
+    }
+    if let Some(reason) = response.get_opt_text_at("failure reason")? {
+        return Err(TrackerError::Failure(reason));
+    }
+
+    let mut stats = BTreeMap::new();
+    for (hash, file) in response.get_dict_at("files")? {
+        let Ok(hash) = <[u8; 20]>::try_from(*hash) else {
+            continue;
+        };
+        stats.insert(hash, ScrapeStats {
+            complete: file.get_u64_at("complete")?,
+            downloaded: file.get_u64_at("downloaded")?,
+            incomplete: file.get_u64_at("incomplete")?,
+        });
+    }
+    Ok(stats)
+}
+
+/// Swarm stats for `info_hashes` from the tracker behind `announce`, batched into as few requests as possible.
+pub fn scrape(announce: &str, info_hashes: &[[u8; 20]]) -> Result<BTreeMap<[u8; 20], ScrapeStats>, TrackerError> {
+    if announce.starts_with("udp://") {
+        // UDP scrapes go to the same address as announces. The client does its own batching.
+        let stats = UdpTrackerClient::from_url(announce)?.scrape(info_hashes)?;
+        return Ok(info_hashes.iter().copied().zip(stats).collect());
+    }
+
+    let url = scrape_url(announce).ok_or_else(|| TrackerError::ScrapeUnsupported(announce.to_string()))?;
+    let mut stats = BTreeMap::new();
+    for batch in info_hashes.chunks(HTTP_SCRAPE_BATCH) {
+        let mut request = url.clone();
+        for hash in batch {
+            append_query_param(&mut request, "info_hash", &escape_hash_to_string(hash));
+        }
+        let response = reqwest::blocking::get(request)?.bytes()?;
+        stats.extend(parse_scrape_response(&response)?);
+    }
+    Ok(stats)
+}
+
+/// Announces to the first tracker in `trackers` that gives a valid response. Returns the response along with
+/// anything non-canonical in its encoding; on failure every tracker's error is in the `AllTrackersFailed`.
+pub fn get_tracker_response(
+    trackers: &mut TrackerTiers,
+    request: &AnnounceRequest,
+) -> Result<(AnnounceResponse, Vec<DecodeWarning>), AllTrackersFailed<TrackerError>> {
+    trackers.try_each(|announce| announce_to(announce, request))
+}
+
+// The URL scheme picks the protocol. UDP responses are fixed-layout binary, so they never have warnings.
+fn announce_to(announce: &str, request: &AnnounceRequest) -> Result<(AnnounceResponse, Vec<DecodeWarning>), TrackerError> {
+    if announce.starts_with("udp://") {
+        return Ok((UdpTrackerClient::from_url(announce)?.announce(request)?, Vec::new()));
+    }
+    let response = reqwest::blocking::get(request.to_url(announce))?.bytes()?;
+    AnnounceResponse::from_bytes_with_warnings(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(t: &[&[&str]]) -> TrackerTiers {
+        TrackerTiers::new(t.iter().map(|tier| tier.iter().map(|s| s.to_string()).collect()).collect())
+    }
+
+    #[test]
+    fn fails_over_and_promotes() {
+        let mut trackers = tiers(&[&["a", "b", "c"], &["d"]]);
+        let mut tried = Vec::new();
+        let response = trackers.try_each(|url| {
+            tried.push(url.to_string());
+            if url == "c" { Ok(url.to_string()) } else { Err("down") }
+        });
+        assert_eq!(response.unwrap(), "c");
+        assert_eq!(tried, vec!["a", "b", "c"]);
+        assert_eq!(trackers, tiers(&[&["c", "a", "b"], &["d"]]));
+
+        // The next tier is only used once the whole first tier has failed.
+        let response = trackers.try_each(|url| if url == "d" { Ok(()) } else { Err(format!("{url} down")) });
+        assert!(response.is_ok());
+        assert_eq!(trackers, tiers(&[&["c", "a", "b"], &["d"]]));
+    }
+
+    #[test]
+    fn reports_every_failure() {
+        let mut trackers = tiers(&[&["a"], &[], &["b"]]);
+        assert_eq!(trackers.tiers().len(), 2);
+        let err = trackers.try_each(|_| Err::<(), _>("timed out")).unwrap_err();
+        assert_eq!(err.0, vec![("a".to_string(), "timed out"), ("b".to_string(), "timed out")]);
+        assert_eq!(err.to_string(), "every tracker failed:\n  a: timed out\n  b: timed out");
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: None,
+            ..AnnounceRequest::for_info_hash([0xAB; 20], *b"-CR0100-abcdefghijkl", 6881)
+        }
+    }
+
+    #[test]
+    fn builds_announce_urls() {
+        let hash = "%AB".repeat(20);
+        let peer_id = escape_hash_to_string(b"-CR0100-abcdefghijkl");
+        let params = format!("info_hash={hash}&peer_id={peer_id}&port=6881&uploaded=1&downloaded=2&left=3&compact=1");
+
+        assert_eq!(request().to_url("http://t/announce"), format!("http://t/announce?{params}"));
+        assert_eq!(request().to_url("http://t/announce?passkey=x"), format!("http://t/announce?passkey=x&{params}"));
+        assert_eq!(request().to_url("http://t/announce?"), format!("http://t/announce?{params}"));
+        assert_eq!(request().to_url("http://t/announce?passkey=x&"), format!("http://t/announce?passkey=x&{params}"));
+
+        // Scrapes repeat info_hash on the same kind of URL.
+        let mut url = "http://t/scrape?passkey=x&".to_string();
+        append_query_param(&mut url, "info_hash", "a");
+        append_query_param(&mut url, "info_hash", "b");
+        assert_eq!(url, "http://t/scrape?passkey=x&info_hash=a&info_hash=b");
+
+        let mut full = request();
+        full.event = Some(AnnounceEvent::Stopped);
+        full.compact = false;
+        full.no_peer_id = true;
+        full.numwant = Some(50);
+        full.key = Some("k1".to_string());
+        full.trackerid = Some("id".to_string());
+        full.ip = Some("10.0.0.1".parse().unwrap());
+        full.ipv6 = Some("::1".parse().unwrap());
+        let url = full.to_url("http://t/a");
+        assert!(url.ends_with(
+            "&compact=0&no_peer_id=1&event=stopped&numwant=50&key=%6B%31&trackerid=%69%64&ip=%31%30%2E%30%2E%30%2E%31&ipv6=%3A%3A%31"
+        ));
+    }
+
+    #[test]
+    fn parses_announce_responses() {
+        let mut bytes = b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e5:peers6:".to_vec();
+        bytes.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+        bytes.extend_from_slice(b"10:tracker id3:xyz15:warning message4:slowe");
+
+        assert_eq!(AnnounceResponse::from_bytes(&bytes).unwrap(), AnnounceResponse {
+            interval: Duration::from_secs(1800),
+            min_interval: Some(Duration::from_secs(60)),
+            tracker_id: Some("xyz".to_string()),
+            complete: Some(5),
+            incomplete: Some(3),
+            warning_message: Some("slow".to_string()),
+            peers: vec![PeerInfo { addr: "127.0.0.1:6881".parse().unwrap(), peer_id: None }],
+        });
+
+        let minimal = AnnounceResponse::from_bytes(b"d8:intervali900e5:peers0:e").unwrap();
+        assert_eq!(minimal.interval, Duration::from_secs(900));
+        assert_eq!((minimal.min_interval, minimal.tracker_id, minimal.complete), (None, None, None));
+    }
+
+    #[test]
+    fn reports_tracker_errors() {
+        let failure = AnnounceResponse::from_bytes(b"d14:failure reason17:torrent not founde");
+        assert!(matches!(&failure, Err(TrackerError::Failure(reason)) if reason == "torrent not found"));
+        assert_eq!(failure.unwrap_err().to_string(), "tracker refused the announce: torrent not found");
+
+        assert!(matches!(AnnounceResponse::from_bytes(b"le"), Err(TrackerError::NotADictionary)));
+        assert!(matches!(AnnounceResponse::from_bytes(b"d5:peers0:e"), Err(TrackerError::Field(QueryError::MissingKey { .. }))));
+        assert!(matches!(AnnounceResponse::from_bytes(b"d8:intervali1ee"), Err(TrackerError::Peers(PeersError::MissingPeers))));
+        assert!(matches!(AnnounceResponse::from_bytes(b"d8:interval"), Err(TrackerError::Decode(_))));
+    }
+
+    #[test]
+    fn derives_scrape_urls() {
+        assert_eq!(scrape_url("http://example.com/announce").as_deref(), Some("http://example.com/scrape"));
+        assert_eq!(scrape_url("http://example.com/x/announce.php?k=a/b").as_deref(), Some("http://example.com/x/scrape.php?k=a/b"));
+        assert_eq!(scrape_url("http://example.com/announce?passkey=1").as_deref(), Some("http://example.com/scrape?passkey=1"));
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn parses_scrape_responses() {
+        let mut bytes = b"d5:filesd20:".to_vec();
+        bytes.extend_from_slice(&[0xAA; 20]);
+        bytes.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        let stats = parse_scrape_response(&bytes).unwrap();
+        assert_eq!(stats, BTreeMap::from([([0xAA; 20], ScrapeStats { complete: 5, downloaded: 50, incomplete: 10 })]));
+
+        assert!(matches!(parse_scrape_response(b"d14:failure reason4:nopee"), Err(TrackerError::Failure(_))));
+        assert!(matches!(parse_scrape_response(b"de"), Err(TrackerError::Field(_))));
+    }
 
-    let BencodeValue::Dictionary(ref dict) = decoded_response else {
-        eprintln!("ERROR: Tracker response is not a dictionary.");
-        return Err(format!("response = {response:#?}").into()); // something;
-    };
-    match dict.get(&b"failure reason"[..]) {
-        Some(e) => {
-            eprintln!("ERROR: Tracker response returned a failure reason. {response:#?}");
-            Err(format!("decoded_response = {decoded_response:#?}").into())
-        } 
-        None => Ok(decoded_response)
-    } 
+    #[test]
+    fn shuffle_stays_within_tiers() {
+        let mut trackers = tiers(&[&["a", "b", "c", "d"], &["e", "f"]]);
+        trackers.shuffle();
+        let mut first = trackers.tiers()[0].clone();
+        first.sort();
+        assert_eq!(first, vec!["a", "b", "c", "d"]);
+        assert_eq!(trackers.tiers()[1].len(), 2);
+    }
 }
@@ -0,0 +1,394 @@
+#![allow(dead_code)]
+
+// BEP 15 UDP tracker protocol. Most public trackers only speak this.
+//
+// Every exchange is one datagram each way, all integers big-endian:
+//   connect:  i64 protocol id, i32 action 0, i32 transaction id  ->  action, transaction id, i64 connection id
+//   announce: connection id, action 1, transaction id, info hash, peer id, downloaded, left, uploaded, event, ip,
+//             key, num want, port  ->  action, transaction id, interval, leechers, seeders, compact peers
+//   scrape:   connection id, action 2, transaction id, info hashes  ->  action, transaction id, then
+//             seeders, completed, leechers for each hash
+//   error:    action 3, transaction id, message
+// UDP can drop packets, so requests are resent after 15 * 2^n seconds. A connection id is good for a minute.
+// The spec keeps resending for about an hour, which would stall failover to the next tracker, so by default we
+// give up after one resend. Every datagram of an announce or scrape, connects included, shares that budget.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::peers::{parse_compact_peers, parse_compact_peers6};
+use crate::tracker_request::{AnnounceEvent, AnnounceRequest, AnnounceResponse, ScrapeStats, TrackerError};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// A scrape request must fit in one datagram.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 1;
+// The full schedule from the spec, for callers with only one tracker to talk to.
+pub const BEP15_MAX_RETRIES: u32 = 8;
+
+// Connection ids are shared by every client talking to the same tracker, so announcing to a tracker again within
+// a minute skips the connect round trip.
+fn connection_cache() -> &'static Mutex<HashMap<SocketAddr, (u64, Instant)>> {
+    static CACHE: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub struct UdpTrackerClient {
+    socket: UdpSocket,
+    tracker: SocketAddr,
+    /// Timeout of the first attempt. Attempt n waits base_timeout * 2^n.
+    pub base_timeout: Duration,
+    /// Resends allowed per announce or scrape before giving up. Set to `BEP15_MAX_RETRIES` for the spec's
+    /// schedule, about an hour in total.
+    pub max_retries: u32,
+}
+
+impl UdpTrackerClient {
+    pub fn new(tracker: SocketAddr) -> Result<UdpTrackerClient, TrackerError> {
+        let bind: SocketAddr = if tracker.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+        Ok(UdpTrackerClient {
+            socket: UdpSocket::bind(bind)?,
+            tracker,
+            base_timeout: Duration::from_secs(15),
+            max_retries: DEFAULT_MAX_RETRIES,
+        })
+    }
+
+    /// Accepts `udp://host:port` with an optional path, which UDP trackers ignore.
+    pub fn from_url(url: &str) -> Result<UdpTrackerClient, TrackerError> {
+        let invalid = || TrackerError::InvalidUrl(url.to_string());
+        let rest = url.strip_prefix("udp://").ok_or_else(invalid)?;
+        let host_port = rest.split(['/', '?']).next().unwrap_or_default();
+        let tracker = host_port.to_socket_addrs().map_err(|_| invalid())?.next().ok_or_else(invalid)?;
+        UdpTrackerClient::new(tracker)
+    }
+
+    pub fn announce(&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&request.info_hash);
+        body.extend_from_slice(&request.peer_id);
+        body.extend_from_slice(&request.downloaded.to_be_bytes());
+        body.extend_from_slice(&request.left.to_be_bytes());
+        body.extend_from_slice(&request.uploaded.to_be_bytes());
+        let event: u32 = match request.event {
+            None => 0,
+            Some(AnnounceEvent::Completed) => 1,
+            Some(AnnounceEvent::Started) => 2,
+            Some(AnnounceEvent::Stopped) => 3,
+        };
+        body.extend_from_slice(&event.to_be_bytes());
+        let ip = match request.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0,
+        };
+        body.extend_from_slice(&ip.to_be_bytes());
+        // HTTP keys are free-form strings; UDP wants a number.
+        let key = request.key.as_deref().and_then(|k| u32::from_str_radix(k, 16).ok()).unwrap_or(0);
+        body.extend_from_slice(&key.to_be_bytes());
+        let num_want = request.numwant.map_or(-1, |n| n.min(i32::MAX as u32) as i32);
+        body.extend_from_slice(&num_want.to_be_bytes());
+        body.extend_from_slice(&request.port.to_be_bytes());
+
+        let response = self.request(ACTION_ANNOUNCE, &body, &mut 0)?;
+        if response.len() < 20 {
+            return Err(TrackerError::InvalidResponse(format!("announce response is {} bytes", response.len())));
+        }
+        let field = |i: usize| u32::from_be_bytes(response[i..i + 4].try_into().unwrap());
+
+        // Peers come in the address family the announce was sent over.
+        let peers = if self.tracker.is_ipv4() { parse_compact_peers(&response[20..]) } else { parse_compact_peers6(&response[20..]) }?;
+        Ok(AnnounceResponse {
+            interval: Duration::from_secs(field(8) as u64),
+            min_interval: None,
+            tracker_id: None,
+            complete: Some(field(16) as u64),
+            incomplete: Some(field(12) as u64),
+            warning_message: None,
+            peers,
+        })
+    }
+
+    /// Stats for each hash, in the same order.
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        let mut attempt = 0;
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let response = self.request(ACTION_SCRAPE, batch.as_flattened(), &mut attempt)?;
+            if response.len() != 8 + 12 * batch.len() {
+                return Err(TrackerError::InvalidResponse(format!(
+                    "scrape response is {} bytes for {} hashes",
+                    response.len(),
+                    batch.len()
+                )));
+            }
+            let field = |i: usize| u32::from_be_bytes(response[i..i + 4].try_into().unwrap()) as u64;
+            stats.extend((0..batch.len()).map(|i| {
+                let at = 8 + 12 * i;
+                ScrapeStats { complete: field(at), downloaded: field(at + 4), incomplete: field(at + 8) }
+            }));
+        }
+        Ok(stats)
+    }
+
+    fn timeout(&self, attempt: u32) -> Duration {
+        self.base_timeout.saturating_mul(1 << attempt.min(16))
+    }
+
+    /// The longest an announce or scrape can wait on a tracker that never answers.
+    pub fn max_wait(&self) -> Duration {
+        (0..=self.max_retries).map(|attempt| self.timeout(attempt)).fold(Duration::ZERO, Duration::saturating_add)
+    }
+
+    // A cached id, or a new one from a connect round trip. None if the tracker didn't answer in time.
+    fn connection_id(&mut self, timeout: Duration) -> Result<Option<u64>, TrackerError> {
+        if let Some(&(id, at)) = connection_cache().lock().unwrap().get(&self.tracker)
+            && at.elapsed() < CONNECTION_ID_LIFETIME
+        {
+            return Ok(Some(id));
+        }
+
+        let transaction_id = rand::random::<u32>();
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let Some(response) = self.exchange(&packet, transaction_id, timeout)? else {
+            return Ok(None);
+        };
+        let response = check_action(response, ACTION_CONNECT)?;
+        if response.len() < 16 {
+            return Err(TrackerError::InvalidResponse(format!("connect response is {} bytes", response.len())));
+        }
+        let id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+        connection_cache().lock().unwrap().insert(self.tracker, (id, Instant::now()));
+        Ok(Some(id))
+    }
+
+    // Sends `body` under a fresh transaction id until a matching reply comes back. Every datagram that goes
+    // unanswered, connect or not, uses up one of `attempt`s, so the whole exchange is bounded by `max_wait`. The
+    // connection id is checked on every attempt because it may expire while we are still retrying.
+    fn request(&mut self, action: u32, body: &[u8], attempt: &mut u32) -> Result<Vec<u8>, TrackerError> {
+        while *attempt <= self.max_retries {
+            let timeout = self.timeout(*attempt);
+            let Some(connection_id) = self.connection_id(timeout)? else {
+                *attempt += 1;
+                continue;
+            };
+            let transaction_id = rand::random::<u32>();
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&action.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            packet.extend_from_slice(body);
+
+            if let Some(response) = self.exchange(&packet, transaction_id, timeout)? {
+                let result = check_action(response, action);
+                if result.is_err() {
+                    // The tracker may have forgotten our connection id.
+                    connection_cache().lock().unwrap().remove(&self.tracker);
+                }
+                return result;
+            }
+            *attempt += 1;
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    // One send and the wait for its reply. Packets from elsewhere or for another transaction (eg. a late reply to
+    // an earlier attempt) are dropped. None on timeout.
+    fn exchange(&self, packet: &[u8], transaction_id: u32, timeout: Duration) -> Result<Option<Vec<u8>>, TrackerError> {
+        self.socket.send_to(packet, self.tracker)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if from != self.tracker || len < 8 || buf[4..8] != transaction_id.to_be_bytes() {
+                continue;
+            }
+            return Ok(Some(buf[..len].to_vec()));
+        }
+    }
+}
+
+fn check_action(response: Vec<u8>, expected: u32) -> Result<Vec<u8>, TrackerError> {
+    let action = u32::from_be_bytes(response[0..4].try_into().unwrap());
+    if action == ACTION_ERROR {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(&response[8..]).into_owned()));
+    }
+    if action != expected {
+        return Err(TrackerError::InvalidResponse(format!("expected action {}, got {}", expected, action)));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker_request::TrackerTiers;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // A tracker stand-in on localhost. `handle` gets each request and returns the datagrams to send back.
+    fn fake_tracker(handle: impl Fn(usize, &[u8]) -> Vec<Vec<u8>> + Send + 'static) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            for n in 0.. {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else { return };
+                for reply in handle(n, &buf[..len]) {
+                    socket.send_to(&reply, from).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    fn reply(action: u32, request: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut r = action.to_be_bytes().to_vec();
+        r.extend_from_slice(&request[12..16]);
+        r.extend_from_slice(payload);
+        r
+    }
+
+    fn connect_reply(request: &[u8]) -> Vec<u8> {
+        assert_eq!(request[..8], PROTOCOL_ID.to_be_bytes());
+        reply(ACTION_CONNECT, request, &0xC0FFEEu64.to_be_bytes())
+    }
+
+    fn client(addr: SocketAddr) -> UdpTrackerClient {
+        let mut client = UdpTrackerClient::new(addr).unwrap();
+        client.base_timeout = Duration::from_millis(100);
+        client.max_retries = 2;
+        client
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest { uploaded: 1, downloaded: 2, left: 3, ..AnnounceRequest::for_info_hash([7; 20], [9; 20], 6881) }
+    }
+
+    #[test]
+    fn announces_and_caches_connection_id() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let seen = connects.clone();
+        let addr = fake_tracker(move |_, req| {
+            if req[8..12] == ACTION_CONNECT.to_be_bytes() {
+                seen.fetch_add(1, Ordering::SeqCst);
+                return vec![connect_reply(req)];
+            }
+            assert_eq!(req.len(), 98);
+            assert_eq!(req[..8], 0xC0FFEEu64.to_be_bytes());
+            assert_eq!(req[16..36], [7; 20]);
+            assert_eq!(req[80..84], 2u32.to_be_bytes()); // started
+            assert_eq!(req[92..96], (-1i32).to_be_bytes());
+            assert_eq!(req[96..98], 6881u16.to_be_bytes());
+            let mut payload = [1800u32.to_be_bytes(), 4u32.to_be_bytes(), 9u32.to_be_bytes()].concat();
+            payload.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]);
+            vec![reply(ACTION_ANNOUNCE, req, &payload)]
+        });
+
+        let mut c = client(addr);
+        let response = c.announce(&request()).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!((response.complete, response.incomplete), (Some(9), Some(4)));
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr, "10.0.0.1:6881".parse().unwrap());
+
+        // A second client for the same tracker reuses the connection id.
+        client(addr).announce(&request()).unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retransmits_and_ignores_other_transactions() {
+        let addr = fake_tracker(|n, req| match n {
+            // Drop the first connect.
+            0 => vec![],
+            1 => vec![connect_reply(req)],
+            // A stale reply with the wrong transaction id, then the real one.
+            _ => {
+                let mut stale = reply(ACTION_SCRAPE, req, &[0; 12]);
+                stale[4] ^= 0xFF;
+                vec![stale, reply(ACTION_SCRAPE, req, &[1, 2, 3, 4, 0, 0, 0, 5, 0, 0, 0, 6])]
+            }
+        });
+
+        let stats = client(addr).scrape(&[[1; 20]]).unwrap();
+        assert_eq!(stats, vec![ScrapeStats { complete: 0x01020304, downloaded: 5, incomplete: 6 }]);
+    }
+
+    #[test]
+    fn reports_errors_and_timeouts() {
+        let addr = fake_tracker(|_, req| {
+            if req[8..12] == ACTION_CONNECT.to_be_bytes() {
+                vec![connect_reply(req)]
+            } else {
+                vec![reply(ACTION_ERROR, req, b"unregistered torrent")]
+            }
+        });
+        assert!(matches!(client(addr).announce(&request()), Err(TrackerError::Failure(m)) if m == "unregistered torrent"));
+
+        let silent = fake_tracker(|_, _| vec![]);
+        let mut c = client(silent);
+        c.base_timeout = Duration::from_millis(10);
+        c.max_retries = 1;
+        assert!(matches!(c.announce(&request()), Err(TrackerError::Timeout)));
+    }
+
+    #[test]
+    fn fails_over_from_a_dead_tracker_in_bounded_time() {
+        // By default a dead tracker costs 15s + 30s, not the spec's hour.
+        assert_eq!(UdpTrackerClient::new("127.0.0.1:1".parse().unwrap()).unwrap().max_wait(), Duration::from_secs(45));
+
+        // Connects go unanswered, so connect and announce retries must not multiply.
+        let dead = fake_tracker(|_, _| vec![]);
+        let live = fake_tracker(|_, req| {
+            if req[8..12] == ACTION_CONNECT.to_be_bytes() {
+                vec![connect_reply(req)]
+            } else {
+                vec![reply(ACTION_ANNOUNCE, req, &[0, 0, 0, 60, 0, 0, 0, 0, 0, 0, 0, 0])]
+            }
+        });
+        let mut trackers = TrackerTiers::new(vec![vec![format!("udp://{dead}"), format!("udp://{live}")]]);
+        let max_wait = client(dead).max_wait();
+
+        let started = Instant::now();
+        let response = trackers.try_each(|url| {
+            let mut c = UdpTrackerClient::from_url(url)?;
+            c.base_timeout = Duration::from_millis(100);
+            c.max_retries = 2;
+            c.announce(&request())
+        });
+        assert_eq!(response.unwrap().interval, Duration::from_secs(60));
+        assert!(started.elapsed() < max_wait + Duration::from_millis(300), "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert!(UdpTrackerClient::from_url("udp://127.0.0.1:6969/announce").is_ok());
+        assert!(matches!(UdpTrackerClient::from_url("http://127.0.0.1:6969"), Err(TrackerError::InvalidUrl(_))));
+        assert!(matches!(UdpTrackerClient::from_url("udp://no-port"), Err(TrackerError::InvalidUrl(_))));
+    }
+}